@@ -0,0 +1,436 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::watch;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::config::duration;
+use crate::event::EventBus;
+use crate::metrics;
+use crate::service::{ServiceCommand, ServiceControl, ServiceStatus};
+
+/// Every running service, by name
+pub type Services = watch::Receiver<BTreeMap<String, ServiceControl>>;
+
+/// HTTP/JSON API to inspect and control the running services.
+///
+/// It's always served on a Unix socket, which is only protected by its file permissions, and
+/// optionally on a TCP address, where every request needs the token as a bearer token.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Unix socket to serve the API on, instead of [`default_socket`]
+    pub socket: Option<PathBuf>,
+    /// TCP address to also serve the API on
+    pub listen: Option<SocketAddr>,
+    /// Bearer token required by the TCP listener
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.listen.is_some() && self.token.as_deref().unwrap_or("").is_empty() {
+            return Err(anyhow!("admin API needs a token to listen on TCP"));
+        }
+        Ok(())
+    }
+}
+
+/// Where the admin socket is when the config doesn't say, and where `ctl` looks for it
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("server-knocker.sock"),
+        None => std::env::temp_dir().join(format!("server-knocker-{}.sock", nix::unistd::getuid())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeepAwake {
+    #[serde(deserialize_with = "duration")]
+    duration: Duration,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Logs {
+    #[serde(default)]
+    follow: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Events {
+    /// Only the events of this service
+    service: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    error: String,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl ToString) -> Self {
+        Self {
+            error: error.to_string(),
+            status,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// Binds every listener of the API, so a bad address fails right away, and serves them in the
+/// background
+///
+/// Failing to bind the default socket, like when another server knocker already uses it, only
+/// leaves the API unavailable.
+pub async fn serve(
+    config: &AdminConfig,
+    services: Services,
+    events: &EventBus,
+) -> anyhow::Result<()> {
+    let path = config.socket.clone().unwrap_or_else(default_socket);
+    match bind_socket(&path) {
+        Ok(listener) => {
+            info!("Admin API listening on {}", path.display());
+            let app = router(services.clone(), events.clone());
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!("Admin API stopped: {}", e);
+                }
+            });
+        }
+        Err(e) if config.socket.is_none() => {
+            warn!("Admin socket is unavailable: {}", e);
+        }
+        Err(e) => return Err(e),
+    }
+
+    if let Some(listen) = config.listen {
+        let token = config
+            .token
+            .clone()
+            .ok_or(anyhow!("admin API needs a token to listen on TCP"))?;
+        let listener = TcpListener::bind(listen)
+            .await
+            .map_err(|e| anyhow!("could not bind admin API to {}: {}", listen, e))?;
+        info!("Admin API listening on {}", listen);
+        let app = router(services, events.clone())
+            .layer(middleware::from_fn_with_state(token, authorize));
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("Admin API stopped: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn bind_socket(path: &std::path::Path) -> anyhow::Result<UnixListener> {
+    // a socket left behind by a previous run would make the bind fail, but one that still
+    // accepts connections belongs to another process, and anything else isn't ours to remove
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{} is already in use", path.display()));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("could not bind admin socket {}: {}", path.display(), e))?;
+    // the socket controls every service without a token, so only its owner may connect
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| anyhow!("could not restrict admin socket {}: {}", path.display(), e))?;
+    Ok(listener)
+}
+
+fn router(services: Services, events: EventBus) -> Router {
+    let bus = Router::new()
+        .route("/events", get(stream_events))
+        .with_state(events);
+    Router::new()
+        .route("/services", get(list))
+        .route("/services/{name}", get(status))
+        .route("/services/{name}/start", post(start))
+        .route("/services/{name}/stop", post(stop))
+        .route("/services/{name}/reset-idle", post(reset_idle))
+        .route("/services/{name}/keep-awake", post(keep_awake))
+        .route("/services/{name}/logs", get(logs))
+        .route("/metrics", get(metrics))
+        .with_state(services)
+        .merge(bus)
+}
+
+async fn authorize(
+    State(token): State<String>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())));
+    if !authorized {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid token"));
+    }
+    Ok(next.run(request).await)
+}
+
+pub fn find(services: &Services, name: &str) -> Result<ServiceControl, ApiError> {
+    services
+        .borrow()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no service named {}", name)))
+}
+
+async fn list(State(services): State<Services>) -> Json<Vec<ServiceStatus>> {
+    Json(services.borrow().values().map(|s| s.status()).collect())
+}
+
+async fn status(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    Ok(Json(find(&services, &name)?.status()))
+}
+
+/// Runs `command` on the service called `name`, responding with its status afterwards
+async fn command(
+    services: &Services,
+    name: &str,
+    command: ServiceCommand,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    let service = find(services, name)?;
+    info!("[{}] Admin API requested {:?}", name, command);
+    service
+        .send(command)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::CONFLICT, e))?;
+    Ok(Json(service.status()))
+}
+
+async fn start(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    command(&services, &name, ServiceCommand::Start).await
+}
+
+async fn stop(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    command(&services, &name, ServiceCommand::Stop).await
+}
+
+async fn reset_idle(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    command(&services, &name, ServiceCommand::ResetIdle).await
+}
+
+async fn keep_awake(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+    Json(body): Json<KeepAwake>,
+) -> Result<Json<ServiceStatus>, ApiError> {
+    command(&services, &name, ServiceCommand::KeepAwake(body.duration)).await
+}
+
+/// Every service's metrics in the Prometheus text format
+async fn metrics(State(services): State<Services>) -> Response {
+    let services: Vec<ServiceControl> = services.borrow().values().cloned().collect();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&services),
+    )
+        .into_response()
+}
+
+/// The last lines of child output as plain text and, with `follow`, every line after them as
+/// they are printed
+async fn logs(
+    State(services): State<Services>,
+    Path(name): Path<String>,
+    Query(query): Query<Logs>,
+) -> Result<Response, ApiError> {
+    let (history, lines) = find(&services, &name)?.logs();
+    let history: String = history.into_iter().map(|l| l + "\n").collect();
+    if !query.follow {
+        return Ok(history.into_response());
+    }
+
+    // lines the client was too slow to read are skipped
+    let lines = BroadcastStream::new(lines).filter_map(|l| l.ok().map(|l| l + "\n"));
+    let body = tokio_stream::once(history)
+        .chain(lines)
+        .map(Ok::<_, std::convert::Infallible>);
+    Ok(Body::from_stream(body).into_response())
+}
+
+/// Every event from now on as newline-delimited JSON, for as long as the client reads them
+async fn stream_events(State(events): State<EventBus>, Query(query): Query<Events>) -> Response {
+    // events the client was too slow to read are skipped
+    let events = BroadcastStream::new(events.subscribe()).filter_map(move |e| {
+        let event = e.ok()?;
+        if query.service.as_ref().is_some_and(|s| *s != event.service) {
+            return None;
+        }
+        serde_json::to_string(&event).ok().map(|e| e + "\n")
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(events.map(Ok::<_, std::convert::Infallible>)),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Request, StatusCode};
+    use axum::middleware;
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{authorize, bind_socket, router, Services};
+    use crate::config::Config;
+    use crate::event::EventBus;
+    use crate::service::state::ServiceState;
+    use crate::service::Service;
+
+    /// Services that aren't running, so their status can be read but commands fail
+    fn services() -> anyhow::Result<(Services, Service)> {
+        let mut config = Config::from_toml(
+            r#"
+            [services.web]
+            listen = ["127.0.0.1:8080"]
+            destination = "127.0.0.1:8081"
+            command = "python -m http.server 8081"
+            "#,
+        )?;
+        let (service, handle) = Service::new(
+            String::from("web"),
+            config.services.remove("web").unwrap(),
+            &EventBus::default(),
+        );
+        let (_, services) =
+            watch::channel(BTreeMap::from([(String::from("web"), handle.control())]));
+        Ok((services, service))
+    }
+
+    #[tokio::test]
+    async fn binds_socket_for_owner_only() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("knocker-admin-{}.sock", std::process::id()));
+        std::fs::write(&path, "not a socket")?;
+        assert!(bind_socket(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "not a socket");
+        std::fs::remove_file(&path)?;
+
+        let listener = bind_socket(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        drop(listener);
+        // a socket nobody listens on anymore is replaced
+        let rebound = bind_socket(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(rebound.is_ok());
+        Ok(())
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn json(response: axum::response::Response) -> anyhow::Result<serde_json::Value> {
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn reports_status() -> anyhow::Result<()> {
+        let (services, service) = services()?;
+        let app = router(services, EventBus::default());
+
+        let response = app.clone().oneshot(request("GET", "/services/web")).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let status = json(response).await?;
+        assert_eq!(
+            status["state"],
+            serde_json::to_value(ServiceState::Stopped)?
+        );
+        assert_eq!(status["connections"], 0);
+        assert!(status["pid"].is_null());
+
+        let response = app.clone().oneshot(request("GET", "/services")).await?;
+        assert_eq!(json(response).await?.as_array().map(Vec::len), Some(1));
+
+        let response = app.clone().oneshot(request("GET", "/services/db")).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // nothing handles the commands of a service that isn't running
+        drop(service);
+        let response = app.oneshot(request("POST", "/services/web/start")).await?;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(json(response).await?["error"].is_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requires_token() -> anyhow::Result<()> {
+        let (services, _service) = services()?;
+        let app = router(services, EventBus::default()).layer(middleware::from_fn_with_state(
+            String::from("hunter2"),
+            authorize,
+        ));
+
+        let response = app.clone().oneshot(request("GET", "/services")).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let mut authorized = request("GET", "/services");
+        authorized
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer hunter2".parse()?);
+        let response = app.oneshot(authorized).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+}
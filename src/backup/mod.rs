@@ -0,0 +1,264 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use log::{debug, info};
+use serde::Deserialize;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Archives of the child's data, made once it stopped cleanly.
+///
+/// Archives are named `<service>-<timestamp>.tar`, or `.tar.zst` when compressed, and are pruned
+/// right after a new one is made.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Files and directories to archive
+    pub paths: Vec<PathBuf>,
+    /// Directory the archives are written to
+    pub destination: PathBuf,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    /// Number of most recent archives to keep
+    pub keep_last: Option<usize>,
+    #[serde(default)]
+    /// Number of days for which the most recent archive is kept
+    pub keep_daily: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "tar",
+            Compression::Zstd => "tar.zst",
+        }
+    }
+}
+
+/// An archive found in the destination directory
+#[derive(Clone, Debug, PartialEq)]
+struct Archive {
+    path: PathBuf,
+    taken: NaiveDateTime,
+}
+
+impl BackupConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.paths.is_empty() {
+            return Err(anyhow!("backup doesn't have any paths"));
+        }
+        if self.keep_last == Some(0) && self.keep_daily.unwrap_or(0) == 0 {
+            return Err(anyhow!("backup retention would remove every archive"));
+        }
+        Ok(())
+    }
+
+    /// Archives the configured paths and prunes old archives, returning the new archive.
+    ///
+    /// This does blocking IO, so it shouldn't run on the async workers.
+    pub fn run(&self, service: &str) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(&self.destination)?;
+
+        let taken = Local::now().naive_local();
+        let name = format!(
+            "{}-{}.{}",
+            service,
+            taken.format(TIMESTAMP_FORMAT),
+            self.compression.extension()
+        );
+        let path = self.destination.join(name);
+        // the archive only gets its final name once complete, so pruning never counts a partial
+        // one
+        let partial = path.with_extension("partial");
+
+        let result = self.write_archive(&partial);
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        std::fs::rename(&partial, &path)?;
+        info!("[{}] Backed up to {}", service, path.display());
+
+        for old in self.expired(self.archives(service)?) {
+            debug!("[{}] Removing old backup {}", service, old.display());
+            std::fs::remove_file(old)?;
+        }
+
+        Ok(path)
+    }
+
+    fn write_archive(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)?;
+        match self.compression {
+            Compression::None => {
+                let mut file = self.append_paths(file)?;
+                file.flush()?;
+            }
+            Compression::Zstd => {
+                let encoder = zstd::Encoder::new(file, 0)?;
+                self.append_paths(encoder)?.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_paths<W: Write>(&self, writer: W) -> anyhow::Result<W> {
+        let mut builder = tar::Builder::new(writer);
+        for path in &self.paths {
+            let name = path
+                .file_name()
+                .ok_or(anyhow!("can't back up {}", path.display()))?;
+            if path.is_dir() {
+                builder.append_dir_all(name, path)?;
+            } else {
+                builder.append_path_with_name(path, name)?;
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    /// Archives of `service` in the destination, newest first
+    fn archives(&self, service: &str) -> anyhow::Result<Vec<Archive>> {
+        let prefix = format!("{}-", service);
+        let suffix = format!(".{}", self.compression.extension());
+
+        let mut archives = Vec::new();
+        for entry in std::fs::read_dir(&self.destination)? {
+            let path = entry?.path();
+            let taken = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix(&prefix))
+                .and_then(|n| n.strip_suffix(&suffix))
+                .and_then(|t| NaiveDateTime::parse_from_str(t, TIMESTAMP_FORMAT).ok());
+            if let Some(taken) = taken {
+                archives.push(Archive { path, taken });
+            }
+        }
+
+        archives.sort_by_key(|a| Reverse(a.taken));
+        Ok(archives)
+    }
+
+    /// Archives that neither retention rule keeps. Without any rule, every archive is kept.
+    fn expired(&self, archives: Vec<Archive>) -> Vec<PathBuf> {
+        if self.keep_last.is_none() && self.keep_daily.is_none() {
+            return Vec::new();
+        }
+
+        let mut days: HashSet<NaiveDate> = HashSet::new();
+        archives
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, archive)| {
+                let recent = self.keep_last.is_some_and(|n| i < n);
+                // archives are sorted newest first, so the first one seen for a day is kept
+                let daily = match self.keep_daily {
+                    Some(n) if days.len() < n => days.insert(archive.taken.date()),
+                    _ => false,
+                };
+                match recent || daily {
+                    true => None,
+                    false => Some(archive.path),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use chrono::NaiveDateTime;
+
+    use super::{Archive, BackupConfig, Compression};
+
+    fn config(keep_last: Option<usize>, keep_daily: Option<usize>) -> BackupConfig {
+        BackupConfig {
+            paths: vec![PathBuf::from("world")],
+            destination: PathBuf::from("backups"),
+            compression: Compression::None,
+            keep_last,
+            keep_daily,
+        }
+    }
+
+    fn archive(taken: &str) -> Archive {
+        Archive {
+            path: PathBuf::from(taken),
+            taken: NaiveDateTime::parse_from_str(taken, "%Y-%m-%d %H:%M").unwrap(),
+        }
+    }
+
+    #[test]
+    fn prunes_with_retention() {
+        let archives = vec![
+            archive("2024-05-03 20:00"),
+            archive("2024-05-03 10:00"),
+            archive("2024-05-02 22:00"),
+            archive("2024-05-02 09:00"),
+            archive("2024-05-01 12:00"),
+        ];
+
+        assert!(config(None, None).expired(archives.clone()).is_empty());
+        assert_eq!(
+            config(Some(2), None).expired(archives.clone()),
+            [
+                PathBuf::from("2024-05-02 22:00"),
+                PathBuf::from("2024-05-02 09:00"),
+                PathBuf::from("2024-05-01 12:00"),
+            ]
+        );
+        assert_eq!(
+            config(Some(1), Some(2)).expired(archives),
+            [
+                PathBuf::from("2024-05-03 10:00"),
+                PathBuf::from("2024-05-02 09:00"),
+                PathBuf::from("2024-05-01 12:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn archives_paths() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("knocker-backup-{}", std::process::id()));
+        let world = dir.join("world");
+        std::fs::create_dir_all(&world)?;
+        std::fs::write(world.join("level.dat"), "level")?;
+
+        let config = BackupConfig {
+            paths: vec![world],
+            destination: dir.join("backups"),
+            compression: Compression::Zstd,
+            keep_last: Some(1),
+            keep_daily: None,
+        };
+        let path = config.run("minecraft")?;
+
+        let decoder = zstd::Decoder::new(std::fs::File::open(&path)?)?;
+        let names = tar::Archive::new(decoder)
+            .entries()?
+            .map(|e| Ok(e?.path()?.into_owned()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert!(names.contains(&PathBuf::from("world/level.dat")));
+        assert_eq!(config.archives("minecraft")?.len(), 1);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
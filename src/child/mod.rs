@@ -1,49 +1,98 @@
-use std::io::BufRead;
-use std::os::unix::process::CommandExt;
-use std::process::{Child, Command, Stdio};
-use std::time::Duration;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
 
 use anyhow::anyhow;
 use nix::libc::{prctl, PR_SET_PDEATHSIG};
 use nix::sys::signal::Signal;
 use nix::unistd::{setsid, Pid};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command};
+use tokio::sync::broadcast;
+
+pub mod rcon;
+pub mod stop;
 
 pub trait LinuxChild {
     fn get_session_id(&self) -> nix::Result<nix::unistd::Pid>;
     fn kill_process_group<T>(&self, signal: T) -> nix::Result<()>
     where
         T: Into<Option<nix::sys::signal::Signal>>;
-    fn try_kill_process_group_after(
-        &self,
-        t: Duration,
-        signal: nix::sys::signal::Signal,
-    ) -> nix::Result<()>;
 }
 
-impl LinuxChild for Child {
-    fn get_session_id(&self) -> nix::Result<nix::unistd::Pid> {
-        nix::unistd::getsid(Some(Pid::from_raw(self.id() as i32)))
+/// Something that happened to a child without the service asking for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChildEvent {
+    /// The child exited on its own, either with an exit `code` or killed by a `signal`
+    Exited {
+        code: Option<i32>,
+        signal: Option<Signal>,
+    },
+}
+
+impl From<ExitStatus> for ChildEvent {
+    fn from(status: ExitStatus) -> Self {
+        ChildEvent::Exited {
+            code: status.code(),
+            signal: status.signal().and_then(|s| Signal::try_from(s).ok()),
+        }
+    }
+}
+
+/// A child application running in its own session.
+///
+/// It is driven by tokio, so waiting on it doesn't block a runtime worker, and it is reaped as
+/// soon as it exits.
+#[derive(Debug)]
+pub struct Child {
+    inner: tokio::process::Child,
+    /// The child is the leader of its session, so this is also its session and process group id.
+    /// Unlike `inner.id()`, it's still known after the child was reaped.
+    pid: Pid,
+    /// Only piped if the service needs to write to the child
+    stdin: Option<ChildStdin>,
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Waits for the child to exit, reaping it
+    pub async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.inner.wait().await
+    }
+
+    /// Whether the child already exited, reaping it if so
+    pub fn has_exited(&mut self) -> bool {
+        self.exit_status().is_some()
     }
 
-    fn try_kill_process_group_after(
-        &self,
-        t: Duration,
-        signal: nix::sys::signal::Signal,
-    ) -> nix::Result<()> {
-        let sid = self.get_session_id()?;
-        tokio::spawn(async move {
-            tokio::time::sleep(t).await;
-            let e = nix::sys::signal::killpg(sid, signal);
-            log::trace!(
-                "trying to signal {} with {} after {}s resulted in {:?}",
-                sid,
-                signal,
-                t.as_secs(),
-                e
-            )
-        });
+    /// How the child exited, if it already did. Once reaped, the status is remembered.
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        self.inner.try_wait().ok().flatten()
+    }
+
+    /// Writes `line` to the child's stdin, as if it was typed into its console
+    pub async fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or(anyhow!("Child stdin is not piped"))?;
+        stdin.write_all(format!("{}\n", line).as_bytes()).await?;
+        stdin.flush().await?;
         Ok(())
     }
+}
+
+impl LinuxChild for Child {
+    fn get_session_id(&self) -> nix::Result<nix::unistd::Pid> {
+        // once the session leader is reaped `getsid` can't find it anymore, but the rest of its
+        // process group may still be alive
+        match self.inner.id() {
+            Some(id) => nix::unistd::getsid(Some(Pid::from_raw(id as i32))),
+            None => Ok(self.pid),
+        }
+    }
 
     fn kill_process_group<T>(&self, signal: T) -> nix::Result<()>
     where
@@ -53,26 +102,45 @@ impl LinuxChild for Child {
     }
 }
 
-pub fn spawn_child(cmd: &str) -> anyhow::Result<Child> {
+/// Spawns `cmd` in its own session, echoing its output and publishing every line of it to `output`.
+///
+/// With `pipe_stdin` the child's stdin can be written to with [`Child::write_line`], otherwise it
+/// inherits ours.
+pub fn spawn_child(
+    cmd: &str,
+    output: &broadcast::Sender<String>,
+    pipe_stdin: bool,
+) -> anyhow::Result<Child> {
     let cmd_words = shell_words::split(cmd)?;
 
+    let mut command = Command::new(&cmd_words[0]);
+    command
+        .args(&cmd_words[1..])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if pipe_stdin {
+        command.stdin(Stdio::piped());
+    }
+
     let mut child = unsafe {
         // Setting a pre_exec hook is unsafe here, because the closure will run
         // in a weird environment between fork an exec.
         // prctl is also unsafe because it calls a libc function directly
         // All other operations are safe
-        Command::new(&cmd_words[0])
-            .args(&cmd_words[1..])
+        command
             .pre_exec(|| {
                 prctl(PR_SET_PDEATHSIG, Signal::SIGTERM as u64, 0, 0, 0);
                 setsid().expect("Failed to set a new session id for child process");
                 Ok(())
             })
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
             .spawn()?
     };
 
+    let pid = child
+        .id()
+        .map(|id| Pid::from_raw(id as i32))
+        .ok_or(anyhow!("Child exited before its id could be read"))?;
+
     let child_stdout = child
         .stdout
         .take()
@@ -82,25 +150,86 @@ pub fn spawn_child(cmd: &str) -> anyhow::Result<Child> {
         .take()
         .ok_or(anyhow!("Couldn't get child stderr"))?;
 
-    tokio::spawn(async {
-        let stdout_lines = std::io::BufReader::new(child_stdout).lines();
-        for line in stdout_lines {
-            match line {
-                Ok(l) => println!("{}", l),
-                Err(_) => break,
-            }
+    let stdout_output = output.clone();
+    tokio::spawn(async move {
+        let mut stdout_lines = BufReader::new(child_stdout).lines();
+        while let Ok(Some(l)) = stdout_lines.next_line().await {
+            println!("{}", l);
+            let _ = stdout_output.send(l);
         }
     });
 
-    tokio::spawn(async {
-        let stderr_lines = std::io::BufReader::new(child_stderr).lines();
-        for line in stderr_lines {
-            match line {
-                Ok(l) => eprintln!("{}", l),
-                Err(_) => break,
-            }
+    let stderr_output = output.clone();
+    tokio::spawn(async move {
+        let mut stderr_lines = BufReader::new(child_stderr).lines();
+        while let Ok(Some(l)) = stderr_lines.next_line().await {
+            eprintln!("{}", l);
+            let _ = stderr_output.send(l);
         }
     });
 
-    Ok(child)
+    Ok(Child {
+        stdin: child.stdin.take(),
+        inner: child,
+        pid,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::signal::Signal::SIGTERM;
+    use tokio::sync::broadcast;
+
+    use super::{spawn_child, ChildEvent, LinuxChild};
+
+    #[tokio::test]
+    async fn spawns_in_own_session() -> anyhow::Result<()> {
+        let (output, mut lines) = broadcast::channel(16);
+        let mut child = spawn_child("sh -c 'echo hello; exec sleep 10'", &output, false)?;
+
+        assert_eq!(child.get_session_id()?.as_raw() as u32, child.id());
+        assert_eq!(lines.recv().await?, "hello");
+
+        let sid = child.get_session_id()?;
+        child.kill_process_group(SIGTERM)?;
+        let status = child.wait().await?;
+        assert_eq!(
+            ChildEvent::from(status),
+            ChildEvent::Exited {
+                code: None,
+                signal: Some(SIGTERM)
+            }
+        );
+
+        // the reaped child still knows its process group
+        assert_eq!(child.get_session_id()?, sid);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_exit_code() -> anyhow::Result<()> {
+        let (output, _) = broadcast::channel(16);
+        let mut child = spawn_child("sh -c 'exit 3'", &output, false)?;
+
+        let status = child.wait().await?;
+        assert_eq!(
+            ChildEvent::from(status),
+            ChildEvent::Exited {
+                code: Some(3),
+                signal: None
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_to_stdin() -> anyhow::Result<()> {
+        let (output, mut lines) = broadcast::channel(16);
+        let mut child = spawn_child("sh -c 'read line; echo got $line'", &output, true)?;
+
+        child.write_line("stop").await?;
+        assert_eq!(lines.recv().await?, "got stop");
+        assert!(child.wait().await?.success());
+        Ok(())
+    }
 }
@@ -0,0 +1,155 @@
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Packet types of the Source RCON protocol, which Minecraft also speaks
+const SERVERDATA_AUTH: i32 = 3;
+const SERVERDATA_AUTH_RESPONSE: i32 = 2;
+const SERVERDATA_EXECCOMMAND: i32 = 2;
+const SERVERDATA_RESPONSE_VALUE: i32 = 0;
+
+/// Larger packets aren't sent by well behaved servers
+const MAX_PACKET_SIZE: i32 = 4096 + 10;
+
+#[derive(Debug, PartialEq)]
+struct Packet {
+    id: i32,
+    kind: i32,
+    body: String,
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    packet: &Packet,
+) -> anyhow::Result<()> {
+    let body = packet.body.as_bytes();
+    // id, type, and the two null terminators
+    let length = body.len() as i32 + 10;
+
+    let mut buf = Vec::with_capacity(length as usize + 4);
+    buf.extend_from_slice(&length.to_le_bytes());
+    buf.extend_from_slice(&packet.id.to_le_bytes());
+    buf.extend_from_slice(&packet.kind.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.extend_from_slice(&[0, 0]);
+
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Packet> {
+    let length = stream.read_i32_le().await?;
+    if !(10..=MAX_PACKET_SIZE).contains(&length) {
+        return Err(anyhow!("invalid RCON packet length {}", length));
+    }
+
+    let id = stream.read_i32_le().await?;
+    let kind = stream.read_i32_le().await?;
+    let mut body = vec![0; length as usize - 8];
+    stream.read_exact(&mut body).await?;
+    body.truncate(body.len() - 2);
+
+    Ok(Packet {
+        id,
+        kind,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Logs into the RCON server at `address` and runs `command`, returning its response
+pub async fn send_command(
+    address: SocketAddr,
+    password: &str,
+    command: &str,
+) -> anyhow::Result<String> {
+    let mut stream = TcpStream::connect(address).await?;
+
+    write_packet(
+        &mut stream,
+        &Packet {
+            id: 1,
+            kind: SERVERDATA_AUTH,
+            body: password.to_owned(),
+        },
+    )
+    .await?;
+    // some servers send an empty response before the actual auth response
+    let auth = loop {
+        let packet = read_packet(&mut stream).await?;
+        if packet.kind == SERVERDATA_AUTH_RESPONSE {
+            break packet;
+        }
+    };
+    if auth.id == -1 {
+        return Err(anyhow!("RCON authentication failed"));
+    }
+
+    write_packet(
+        &mut stream,
+        &Packet {
+            id: 2,
+            kind: SERVERDATA_EXECCOMMAND,
+            body: command.to_owned(),
+        },
+    )
+    .await?;
+
+    // the server may close the connection right away when the command stops it
+    match read_packet(&mut stream).await {
+        Ok(response) if response.kind == SERVERDATA_RESPONSE_VALUE => Ok(response.body),
+        Ok(response) => Err(anyhow!("unexpected RCON packet type {}", response.kind)),
+        Err(_) => Ok(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::{
+        read_packet, send_command, write_packet, Packet, SERVERDATA_AUTH_RESPONSE,
+        SERVERDATA_EXECCOMMAND, SERVERDATA_RESPONSE_VALUE,
+    };
+
+    #[tokio::test]
+    async fn sends_command() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+
+            let auth = read_packet(&mut stream).await?;
+            let id = if auth.body == "secret" { auth.id } else { -1 };
+            write_packet(
+                &mut stream,
+                &Packet {
+                    id,
+                    kind: SERVERDATA_AUTH_RESPONSE,
+                    body: String::new(),
+                },
+            )
+            .await?;
+
+            let command = read_packet(&mut stream).await?;
+            assert_eq!(command.kind, SERVERDATA_EXECCOMMAND);
+            write_packet(
+                &mut stream,
+                &Packet {
+                    id: command.id,
+                    kind: SERVERDATA_RESPONSE_VALUE,
+                    body: format!("ran {}", command.body),
+                },
+            )
+            .await?;
+            anyhow::Ok(())
+        });
+
+        assert_eq!(send_command(address, "secret", "stop").await?, "ran stop");
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{debug, trace, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{killpg, Signal};
+use serde::Deserialize;
+use tokio::time::{sleep_until, Instant};
+
+use super::{rcon, Child, LinuxChild};
+
+/// Time the process group has to exit after the last step of a plan before giving up on it
+const FINAL_WAIT: Duration = Duration::from_secs(5);
+
+/// Signal sent to the process group `after` the stop started
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StopStep {
+    pub signal: Signal,
+    pub after: Duration,
+}
+
+impl fmt::Display for StopStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}s", self.signal, self.after.as_secs_f64())
+    }
+}
+
+/// Escalating signals used to stop a child, written like `SIGINT@0s -> SIGTERM@30s -> SIGKILL@60s`.
+///
+/// Steps run in order. Before each of them the process group is checked, and the remaining steps
+/// are cancelled once it's gone, so a reused process group id never gets signalled.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct StopPlan {
+    steps: Vec<StopStep>,
+}
+
+/// How the child is asked to stop before falling back to the signals of its [`StopPlan`]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StopStrategy {
+    /// Go straight to the stop plan
+    #[default]
+    Signal,
+    /// Write `input` to the child's stdin, like `stop` for a Minecraft server console
+    Stdin { input: String },
+    /// Run `command`, like `docker stop minecraft`
+    Exec { command: String },
+    /// Send `command` to the Minecraft RCON server at `address`
+    Rcon {
+        address: SocketAddr,
+        password: String,
+        #[serde(default = "default_rcon_command")]
+        command: String,
+    },
+}
+
+fn default_rcon_command() -> String {
+    String::from("stop")
+}
+
+impl StopStrategy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let StopStrategy::Exec { command } = self {
+            if shell_words::split(command)?.is_empty() {
+                return Err(anyhow!("exec stop strategy has an empty command"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a stop ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopOutcome {
+    /// The process group is gone after the [`StopStrategy`] asked it to stop
+    Requested,
+    /// The process group is gone after the given step, or before any step if `None`
+    Stopped(Option<StopStep>),
+    /// Processes in the group outlived the whole plan
+    StillAlive,
+}
+
+impl StopPlan {
+    /// The classic termination: SIGTERM, and SIGKILL if the group is still around after
+    /// `grace_period`
+    pub fn terminate(grace_period: Duration) -> Self {
+        Self {
+            steps: vec![
+                StopStep {
+                    signal: Signal::SIGTERM,
+                    after: Duration::ZERO,
+                },
+                StopStep {
+                    signal: Signal::SIGKILL,
+                    after: grace_period,
+                },
+            ],
+        }
+    }
+
+    pub fn steps(&self) -> &[StopStep] {
+        &self.steps
+    }
+}
+
+impl FromStr for StopPlan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps: Vec<StopStep> = Vec::new();
+        for step in s.split("->") {
+            let (signal, after) = step.trim().split_once('@').ok_or(anyhow!(
+                "stop step {:?} should look like SIGTERM@30s",
+                step.trim()
+            ))?;
+
+            let signal = signal.trim().to_uppercase();
+            let signal = match signal.starts_with("SIG") {
+                true => Signal::from_str(&signal),
+                false => Signal::from_str(&format!("SIG{}", signal)),
+            }
+            .map_err(|_| anyhow!("unknown signal {}", signal))?;
+            let after = parse_duration::parse(after.trim())?;
+
+            if let Some(last) = steps.last() {
+                if after < last.after {
+                    return Err(anyhow!("stop step {} runs before {}", step.trim(), last));
+                }
+            }
+            steps.push(StopStep { signal, after });
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+impl TryFrom<String> for StopPlan {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for StopPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+impl Child {
+    /// Whether any process of the child's group is still running, reaping the child if it exited
+    fn group_alive(&mut self) -> bool {
+        // the child stays in the group as a zombie until it is reaped
+        if let Err(e) = self.inner.try_wait() {
+            debug!("Failed to check on child {}: {}", self.pid, e);
+        }
+        !matches!(killpg(self.pid, None), Err(Errno::ESRCH))
+    }
+
+    /// Waits until the process group is gone, or `deadline` is reached. Returns whether it's gone.
+    async fn wait_group_exit(&mut self, deadline: Instant) -> bool {
+        loop {
+            if !self.group_alive() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            sleep_until(deadline.min(now + Duration::from_millis(100))).await;
+        }
+    }
+
+    /// Asks the child to stop as described by `strategy`
+    async fn request_stop(&mut self, strategy: &StopStrategy) -> anyhow::Result<()> {
+        match strategy {
+            StopStrategy::Signal => {}
+            StopStrategy::Stdin { input } => self.write_line(input).await?,
+            StopStrategy::Exec { command } => {
+                let words = shell_words::split(command)?;
+                let status = tokio::process::Command::new(&words[0])
+                    .args(&words[1..])
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                if !status.success() {
+                    return Err(anyhow!("stop command exited with {}", status));
+                }
+            }
+            StopStrategy::Rcon {
+                address,
+                password,
+                command,
+            } => {
+                let response = rcon::send_command(*address, password, command).await?;
+                debug!("RCON answered {:?}", response);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the child's process group, reaping the child on the way.
+    ///
+    /// Unless the strategy is [`StopStrategy::Signal`], the child is first asked to stop and gets
+    /// `grace_period` to do it before `plan` runs. A child that already exited isn't asked anything,
+    /// what's left of its group goes straight to the plan.
+    pub async fn stop(
+        &mut self,
+        strategy: &StopStrategy,
+        grace_period: Duration,
+        plan: &StopPlan,
+    ) -> StopOutcome {
+        if *strategy != StopStrategy::Signal && !self.has_exited() {
+            let deadline = Instant::now() + grace_period;
+            // a hanging request counts against the grace period too
+            match tokio::time::timeout_at(deadline, self.request_stop(strategy)).await {
+                Ok(Ok(())) => {
+                    if self.wait_group_exit(deadline).await {
+                        return StopOutcome::Requested;
+                    }
+                    debug!("Child {} ignored the stop request", self.pid);
+                }
+                Ok(Err(e)) => warn!("Failed to ask child {} to stop: {}", self.pid, e),
+                Err(_) => warn!("Asking child {} to stop timed out", self.pid),
+            }
+        }
+
+        let started = Instant::now();
+        let mut last = None;
+        for step in plan.steps() {
+            if self.wait_group_exit(started + step.after).await {
+                return StopOutcome::Stopped(last);
+            }
+
+            let result = self.kill_process_group(step.signal);
+            trace!("sending {} to {} resulted in {:?}", step, self.pid, result);
+            if let Err(Errno::ESRCH) = result {
+                return StopOutcome::Stopped(last);
+            }
+            last = Some(*step);
+        }
+
+        if self.wait_group_exit(Instant::now() + FINAL_WAIT).await {
+            StopOutcome::Stopped(last)
+        } else {
+            StopOutcome::StillAlive
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use nix::sys::signal::Signal::{SIGINT, SIGKILL, SIGTERM};
+    use tokio::sync::broadcast;
+
+    use super::{StopOutcome, StopPlan, StopStep, StopStrategy};
+    use crate::child::spawn_child;
+
+    #[test]
+    fn parses_plan() -> anyhow::Result<()> {
+        let plan: StopPlan = "SIGINT@0s -> TERM@30s -> SIGKILL@1m".parse()?;
+        assert_eq!(
+            plan.steps(),
+            [
+                StopStep {
+                    signal: SIGINT,
+                    after: Duration::ZERO
+                },
+                StopStep {
+                    signal: SIGTERM,
+                    after: Duration::from_secs(30)
+                },
+                StopStep {
+                    signal: SIGKILL,
+                    after: Duration::from_secs(60)
+                },
+            ]
+        );
+        assert_eq!(plan.to_string(), "SIGINT@0s -> SIGTERM@30s -> SIGKILL@60s");
+
+        assert!("SIGTERM".parse::<StopPlan>().is_err());
+        assert!("SIGNOPE@1s".parse::<StopPlan>().is_err());
+        assert!("SIGTERM@10s -> SIGKILL@5s".parse::<StopPlan>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn escalates_until_group_is_gone() -> anyhow::Result<()> {
+        let (output, _) = broadcast::channel(16);
+        // ignores SIGINT, so only the second step stops it
+        let mut child = spawn_child("sh -c 'trap \"\" INT; exec sleep 10'", &output, false)?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let plan: StopPlan = "SIGINT@0s -> SIGTERM@200ms -> SIGKILL@5s".parse()?;
+        assert_eq!(
+            child
+                .stop(&StopStrategy::Signal, Duration::ZERO, &plan)
+                .await,
+            StopOutcome::Stopped(Some(plan.steps()[1]))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_steps_once_exited() -> anyhow::Result<()> {
+        let (output, _) = broadcast::channel(16);
+        let mut child = spawn_child("true", &output, false)?;
+        child.wait().await?;
+
+        let plan = StopPlan::terminate(Duration::from_secs(5));
+        assert_eq!(
+            child
+                .stop(&StopStrategy::Signal, Duration::ZERO, &plan)
+                .await,
+            StopOutcome::Stopped(None)
+        );
+
+        // a dead child isn't asked to stop, which would take the whole grace period here
+        let strategy = StopStrategy::Exec {
+            command: String::from("sleep 10"),
+        };
+        let stopped = child.stop(&strategy, Duration::from_secs(10), &plan);
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), stopped).await?,
+            StopOutcome::Stopped(None)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asks_through_stdin_first() -> anyhow::Result<()> {
+        let (output, _) = broadcast::channel(16);
+        let mut child = spawn_child(
+            "sh -c 'trap \"\" TERM; while read line; do [ $line = stop ] && exit; done'",
+            &output,
+            true,
+        )?;
+
+        let strategy = StopStrategy::Stdin {
+            input: String::from("stop"),
+        };
+        let plan = StopPlan::terminate(Duration::from_secs(5));
+        assert_eq!(
+            child.stop(&strategy, Duration::from_secs(5), &plan).await,
+            StopOutcome::Requested
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+
+use crate::admin::AdminConfig;
+use crate::backup::BackupConfig;
+use crate::child::stop::{StopPlan, StopStrategy};
+use crate::dashboard::DashboardConfig;
+use crate::mqtt::MqttConfig;
+use crate::readiness::ReadinessConfig;
+use crate::service::hook::Hooks;
+use crate::service::warning::{ShutdownWarnings, WarningAction};
+use crate::webhook::WebhooksConfig;
+
+/// Description of every service a single server knocker process supervises.
+///
+/// It can be read from a TOML or YAML file, where each entry in `services` is keyed by the service
+/// name:
+///
+/// ```toml
+/// [services.minecraft]
+/// listen = ["0.0.0.0:25565"]
+/// destination = "127.0.0.1:25566"
+/// command = "java -jar server.jar --port 25566"
+/// idle_timeout = "30m"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceConfig>,
+    #[serde(default)]
+    /// API to inspect and control the services while they run
+    pub admin: AdminConfig,
+    #[serde(default)]
+    /// Web page to see the services and wake them up
+    pub dashboard: Option<DashboardConfig>,
+    #[serde(default)]
+    /// HTTP requests made when something happens to the services
+    pub webhooks: WebhooksConfig,
+    #[serde(default)]
+    /// File every time a service was awake is appended to as a JSON line
+    pub ledger: Option<PathBuf>,
+    #[serde(default)]
+    /// File every event is appended to as a JSON line
+    pub event_log: Option<PathBuf>,
+    #[serde(default)]
+    /// MQTT broker to publish the services to, and take commands from
+    pub mqtt: Option<MqttConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// Addresses for the proxy to listen on
+    pub listen: Vec<SocketAddr>,
+    /// Destination address for the proxy
+    pub destination: SocketAddr,
+    /// Command to run as a child
+    pub command: String,
+
+    #[serde(default = "default_idle_timeout", deserialize_with = "duration")]
+    /// Time between received packets to consider the child application idle
+    pub idle_timeout: Duration,
+    #[serde(default)]
+    /// Announcements made to the child's users before it is stopped for being idle
+    pub shutdown_warnings: Option<ShutdownWarnings>,
+    #[serde(default = "default_grace_period", deserialize_with = "duration")]
+    /// Time to wait between trying to terminate the idle application (SIGTERM) and killing it
+    /// (SIGKILL)
+    pub grace_period: Duration,
+    #[serde(default)]
+    /// How the child is asked to stop before it gets signals
+    pub stop: StopStrategy,
+    #[serde(default)]
+    /// Signals used to stop the child, like `"SIGINT@0s -> SIGTERM@30s -> SIGKILL@60s"`. Replaces
+    /// the `grace_period` when set.
+    pub stop_plan: Option<StopPlan>,
+
+    #[serde(default)]
+    /// For TCP proxies: hold off the request until the child is ready if it was down
+    pub hold_packets: bool,
+    #[serde(default)]
+    /// Probe deciding when a freshly spawned child is ready, releasing held connections.
+    ///
+    /// Without it, held connections are released as soon as the child is spawned.
+    pub readiness: Option<ReadinessConfig>,
+    #[serde(default, deserialize_with = "optional_duration")]
+    /// Time the child has to become ready before its start is considered failed
+    pub startup_timeout: Option<Duration>,
+    #[serde(default = "default_start_backoff", deserialize_with = "duration")]
+    /// Time to refuse wake ups for after a failed start. It doubles with every consecutive
+    /// failure, up to `max_start_backoff`.
+    pub start_backoff: Duration,
+    #[serde(default = "default_max_start_backoff", deserialize_with = "duration")]
+    pub max_start_backoff: Duration,
+    #[serde(default)]
+    /// For TCP proxies: data written to held connections before closing them when the child fails
+    /// to start, like `"HTTP/1.1 503 Service Unavailable\r\n\r\n"`
+    pub start_failure_response: Option<String>,
+    #[serde(default)]
+    /// Archives of the child's data, made after it stops cleanly
+    pub backup: Option<BackupConfig>,
+    #[serde(default)]
+    /// Commands run around the child's lifecycle
+    pub hooks: Hooks,
+    #[serde(default)]
+    /// What to do when the child exits without being stopped
+    pub restart: RestartConfig,
+    #[serde(default)]
+    /// Whether to use UDP instead of the default TCP for the proxy
+    pub udp: bool,
+}
+
+/// What happens to a child that exits on its own, instead of being stopped by the service
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// The service stays stopped until the next connection wakes it up
+    #[default]
+    Never,
+    /// The child is started again right away
+    Always,
+    /// The child is started again after `backoff`, doubling with every crash in the crash window
+    Backoff,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RestartConfig {
+    #[serde(default)]
+    pub policy: RestartPolicy,
+    #[serde(default = "default_restart_backoff", deserialize_with = "duration")]
+    /// Time to wait before restarting after the first crash, for the `backoff` policy
+    pub backoff: Duration,
+    #[serde(default = "default_max_restart_backoff", deserialize_with = "duration")]
+    pub max_backoff: Duration,
+    #[serde(default)]
+    /// Number of crashes within `crash_window` after which the child isn't restarted anymore
+    pub max_crashes: Option<u32>,
+    #[serde(default = "default_crash_window", deserialize_with = "duration")]
+    /// How long a crash counts towards `max_crashes` and the backoff
+    pub crash_window: Duration,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::default(),
+            backoff: default_restart_backoff(),
+            max_backoff: default_max_restart_backoff(),
+            max_crashes: None,
+            crash_window: default_crash_window(),
+        }
+    }
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_grace_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+pub fn default_start_backoff() -> Duration {
+    Duration::from_secs(10)
+}
+
+pub fn default_max_start_backoff() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_restart_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_max_restart_backoff() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_crash_window() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+/// Deserializes a human readable duration such as `1h30m` or `10s`
+pub fn duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration::parse(&s).map_err(serde::de::Error::custom)
+}
+
+/// Same as [`duration`], for optional fields
+pub fn optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    duration(deserializer).map(Some)
+}
+
+impl Config {
+    /// Reads a config file, using its extension to decide between YAML (`.yaml`/`.yml`) and TOML
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("could not read config {}: {}", path.display(), e))?;
+
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents)?,
+            _ => Self::from_toml(&contents)?,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn from_yaml(contents: &str) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_str(contents)?)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.services.is_empty() {
+            return Err(anyhow!("config does not declare any services"));
+        }
+        self.admin.validate()?;
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.validate()?;
+        }
+        self.webhooks.validate()?;
+        if let Some(ref mqtt) = self.mqtt {
+            mqtt.validate()?;
+        }
+
+        for (name, service) in &self.services {
+            if service.listen.is_empty() {
+                return Err(anyhow!("service {} does not listen on any address", name));
+            }
+            if shell_words::split(&service.command)?.is_empty() {
+                return Err(anyhow!("service {} has an empty command", name));
+            }
+            if let Some(ref readiness) = service.readiness {
+                readiness.validate().map_err(|e| {
+                    anyhow!("service {} has an invalid readiness probe: {}", name, e)
+                })?;
+            }
+            if let Some(ref warnings) = service.shutdown_warnings {
+                warnings.validate(service.idle_timeout).map_err(|e| {
+                    anyhow!("service {} has invalid shutdown warnings: {}", name, e)
+                })?;
+            }
+            if let Some(ref backup) = service.backup {
+                backup
+                    .validate()
+                    .map_err(|e| anyhow!("service {} has an invalid backup: {}", name, e))?;
+            }
+            service
+                .hooks
+                .validate()
+                .map_err(|e| anyhow!("service {} has an invalid hook: {}", name, e))?;
+            service
+                .stop
+                .validate()
+                .map_err(|e| anyhow!("service {} has an invalid stop strategy: {}", name, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ServiceConfig {
+    /// Whether moving from this configuration to `other` needs the proxies to be recreated.
+    ///
+    /// Everything else (timeouts, the command) can be applied to a running service.
+    pub fn requires_restart(&self, other: &ServiceConfig) -> bool {
+        self.listen != other.listen
+            || self.destination != other.destination
+            || self.udp != other.udp
+            || self.hold_packets != other.hold_packets
+            || self.start_failure_response != other.start_failure_response
+    }
+
+    /// Whether the service writes to the child's stdin
+    pub fn needs_stdin(&self) -> bool {
+        matches!(self.stop, StopStrategy::Stdin { .. })
+            || matches!(
+                self.shutdown_warnings,
+                Some(ShutdownWarnings {
+                    action: WarningAction::Stdin { .. },
+                    ..
+                })
+            )
+    }
+
+    pub fn stop_plan(&self) -> StopPlan {
+        match self.stop_plan {
+            Some(ref plan) => plan.clone(),
+            None => StopPlan::terminate(self.grace_period),
+        }
+    }
+
+    /// Time to refuse wake ups for after `failures` consecutive failed starts
+    pub fn start_backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.start_backoff
+            .saturating_mul(factor)
+            .min(self.max_start_backoff)
+    }
+}
+
+impl RestartConfig {
+    /// Time to wait before restarting a child that crashed `crashes` times within the crash
+    /// window, or `None` if it should stay stopped
+    pub fn delay(&self, crashes: u32) -> Option<Duration> {
+        if self.max_crashes.is_some_and(|max| crashes >= max) {
+            return None;
+        }
+
+        match self.policy {
+            RestartPolicy::Never => None,
+            RestartPolicy::Always => Some(Duration::ZERO),
+            RestartPolicy::Backoff => {
+                let factor = 2u32.saturating_pow(crashes.saturating_sub(1));
+                Some(self.backoff.saturating_mul(factor).min(self.max_backoff))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{Config, RestartConfig, RestartPolicy};
+    use crate::backup::{BackupConfig, Compression};
+    use crate::child::stop::StopStrategy;
+    use crate::readiness::Probe;
+
+    #[test]
+    fn parses_toml() -> anyhow::Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [services.minecraft]
+            listen = ["0.0.0.0:25565", "[::]:25565"]
+            destination = "127.0.0.1:25566"
+            command = "java -jar server.jar"
+            idle_timeout = "30m"
+
+            [services.minecraft.readiness]
+            type = "tcp"
+            interval = "2s"
+
+            [services.minecraft.stop]
+            type = "rcon"
+            address = "127.0.0.1:25575"
+            password = "hunter2"
+
+            [services.valheim]
+            listen = ["0.0.0.0:2456"]
+            destination = "127.0.0.1:2457"
+            command = "./valheim_server"
+            udp = true
+
+            [admin]
+            socket = "/run/server-knocker.sock"
+
+            [dashboard]
+            listen = "0.0.0.0:8080"
+            password = "hunter2"
+
+            [webhooks.targets.discord]
+            url = "https://discord.com/api/webhooks/123/abc"
+            events = ["service_waking", "ready"]
+            body = '{"content": "{{message}}"}'
+            "#,
+        )?;
+        config.validate()?;
+
+        let minecraft = &config.services["minecraft"];
+        assert_eq!(minecraft.listen.len(), 2);
+        assert_eq!(minecraft.idle_timeout, Duration::from_secs(30 * 60));
+        assert_eq!(minecraft.grace_period, Duration::from_secs(10));
+        assert!(!minecraft.udp);
+        let readiness = minecraft.readiness.as_ref().unwrap();
+        assert_eq!(readiness.probe, Probe::Tcp { address: None });
+        assert_eq!(readiness.interval, Duration::from_secs(2));
+        assert!(config.services["valheim"].readiness.is_none());
+        assert!(minecraft.startup_timeout.is_none());
+        assert_eq!(
+            minecraft.stop,
+            StopStrategy::Rcon {
+                address: "127.0.0.1:25575".parse()?,
+                password: String::from("hunter2"),
+                command: String::from("stop"),
+            }
+        );
+        assert_eq!(config.services["valheim"].stop, StopStrategy::Signal);
+        assert!(config.services["valheim"].udp);
+        let admin = &config.admin;
+        assert_eq!(
+            admin.socket,
+            Some(PathBuf::from("/run/server-knocker.sock"))
+        );
+        assert!(admin.listen.is_none());
+        let dashboard = config.dashboard.as_ref().unwrap();
+        assert_eq!(dashboard.listen, "0.0.0.0:8080".parse()?);
+        let discord = &config.webhooks.targets["discord"];
+        assert_eq!(discord.method, "POST");
+        assert_eq!(discord.retries, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_yaml() -> anyhow::Result<()> {
+        let config = Config::from_yaml(
+            r#"
+            services:
+              web:
+                listen: ["127.0.0.1:8000"]
+                destination: "127.0.0.1:8001"
+                command: "./webserver.sh"
+                grace_period: "1m"
+                stop_plan: "SIGINT@0s -> SIGKILL@2m"
+                hold_packets: true
+                readiness:
+                  type: http
+                  url: "http://127.0.0.1:8001/health"
+                  success_threshold: 2
+                startup_timeout: "2m"
+                backup:
+                  paths: ["data"]
+                  destination: "/var/backups/web"
+                  compression: zstd
+                  keep_daily: 7
+            "#,
+        )?;
+        config.validate()?;
+
+        let web = &config.services["web"];
+        assert_eq!(web.grace_period, Duration::from_secs(60));
+        assert_eq!(web.stop_plan().to_string(), "SIGINT@0s -> SIGKILL@120s");
+        assert_eq!(web.stop_plan, Some("SIGINT@0s -> SIGKILL@2m".parse()?));
+        assert!(web.hold_packets);
+
+        let readiness = web.readiness.as_ref().unwrap();
+        assert_eq!(
+            readiness.probe,
+            Probe::Http {
+                url: String::from("http://127.0.0.1:8001/health"),
+                status: 200
+            }
+        );
+        assert_eq!(readiness.success_threshold, 2);
+        assert_eq!(web.startup_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(
+            web.backup,
+            Some(BackupConfig {
+                paths: vec![PathBuf::from("data")],
+                destination: PathBuf::from("/var/backups/web"),
+                compression: Compression::Zstd,
+                keep_last: None,
+                keep_daily: Some(7),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn start_backoff_doubles() -> anyhow::Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [services.web]
+            listen = ["127.0.0.1:8000"]
+            destination = "127.0.0.1:8001"
+            command = "./webserver.sh"
+            start_backoff = "10s"
+            max_start_backoff = "1m"
+            "#,
+        )?;
+
+        let web = &config.services["web"];
+        assert_eq!(web.start_backoff(1), Duration::from_secs(10));
+        assert_eq!(web.start_backoff(2), Duration::from_secs(20));
+        assert_eq!(web.start_backoff(3), Duration::from_secs(40));
+        assert_eq!(web.start_backoff(4), Duration::from_secs(60));
+        assert_eq!(web.start_backoff(100), Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[test]
+    fn restart_gives_up_after_max_crashes() -> anyhow::Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [services.web]
+            listen = ["127.0.0.1:8000"]
+            destination = "127.0.0.1:8001"
+            command = "./webserver.sh"
+
+            [services.web.restart]
+            policy = "backoff"
+            backoff = "2s"
+            max_crashes = 3
+            "#,
+        )?;
+
+        let restart = &config.services["web"].restart;
+        assert_eq!(restart.policy, RestartPolicy::Backoff);
+        assert_eq!(restart.delay(1), Some(Duration::from_secs(2)));
+        assert_eq!(restart.delay(2), Some(Duration::from_secs(4)));
+        assert_eq!(restart.delay(3), None);
+
+        let default = RestartConfig::default();
+        assert_eq!(default.delay(1), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_service_without_listeners() -> anyhow::Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [services.empty]
+            listen = []
+            destination = "127.0.0.1:8001"
+            command = "true"
+            "#,
+        )?;
+
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+}
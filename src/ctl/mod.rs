@@ -0,0 +1,329 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local, Utc};
+use clap::{Args, Subcommand};
+use serde::Deserialize;
+
+use crate::admin;
+use crate::service::ServiceStatus;
+
+#[derive(Clone, Debug, Args)]
+pub struct Ctl {
+    #[arg(long, global = true)]
+    /// Admin socket of the server knocker to talk to
+    ///
+    /// Defaults to `$XDG_RUNTIME_DIR/server-knocker.sock`, just like the server's.
+    socket: Option<PathBuf>,
+    #[arg(long, global = true, default_value_t = false)]
+    /// Print the admin API's JSON instead of a table
+    json: bool,
+
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum CtlCommand {
+    /// Shows every service, or a single one
+    Status { service: Option<String> },
+    /// Starts a service's child now, even if its last start failed recently
+    Start { service: String },
+    /// Stops a service's child now, as if it was idle
+    Stop { service: String },
+    /// Counts a service's idle timeout from now
+    ResetIdle { service: String },
+    /// Keeps a service's child up for at least `duration`, starting it if needed
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    KeepAwake { service: String, duration: String },
+    /// Prints the last lines of a service's child output
+    Logs {
+        service: String,
+        #[arg(short, long, default_value_t = false)]
+        /// Keep printing the child output as it comes
+        follow: bool,
+    },
+    /// Prints the events of every service, or a single one, as JSON lines while they happen
+    Events { service: Option<String> },
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    error: String,
+}
+
+/// Talks HTTP to the admin API over its Unix socket
+pub struct Client {
+    socket: PathBuf,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(socket: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            socket: socket.to_owned(),
+            http: reqwest::Client::builder().unix_socket(socket).build()?,
+        })
+    }
+
+    /// Sends `request`, turning the API's errors into ours
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        let response = request.send().await.map_err(|e| {
+            anyhow!(
+                "could not reach server knocker at {}: {}",
+                self.socket.display(),
+                e
+            )
+        })?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        match response.json::<ApiError>().await {
+            Ok(e) => Err(anyhow!(e.error)),
+            Err(_) => Err(anyhow!("admin API responded with {}", status)),
+        }
+    }
+
+    /// The admin API's URL for the path made of `segments`, each of them percent-encoded so a
+    /// service name can't reach another route
+    pub fn url(segments: &[&str]) -> reqwest::Url {
+        let mut url = reqwest::Url::parse("http://localhost").expect("valid URL");
+        url.path_segments_mut()
+            .expect("HTTP URLs have a path")
+            .extend(segments);
+        url
+    }
+
+    pub async fn get(&self, url: reqwest::Url) -> anyhow::Result<reqwest::Response> {
+        self.send(self.http.get(url)).await
+    }
+
+    pub async fn post(
+        &self,
+        url: reqwest::Url,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut request = self.http.post(url);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        self.send(request).await
+    }
+}
+
+impl Ctl {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let socket = self.socket.clone().unwrap_or_else(admin::default_socket);
+        let client = Client::new(&socket)?;
+
+        let response = match self.command {
+            CtlCommand::Status { service: None } => client.get(Client::url(&["services"])).await?,
+            CtlCommand::Status {
+                service: Some(ref service),
+            } => client.get(Client::url(&["services", service])).await?,
+            CtlCommand::Start { ref service } => {
+                client
+                    .post(Client::url(&["services", service, "start"]), None)
+                    .await?
+            }
+            CtlCommand::Stop { ref service } => {
+                client
+                    .post(Client::url(&["services", service, "stop"]), None)
+                    .await?
+            }
+            CtlCommand::ResetIdle { ref service } => {
+                client
+                    .post(Client::url(&["services", service, "reset-idle"]), None)
+                    .await?
+            }
+            CtlCommand::KeepAwake {
+                ref service,
+                ref duration,
+            } => {
+                // checked here so a typo gets the same message as any other flag
+                parse_duration::parse(duration)?;
+                let body = serde_json::json!({ "duration": duration });
+                client
+                    .post(
+                        Client::url(&["services", service, "keep-awake"]),
+                        Some(body),
+                    )
+                    .await?
+            }
+            CtlCommand::Logs { .. } | CtlCommand::Events { .. } => {
+                let url = match self.command {
+                    CtlCommand::Logs {
+                        ref service,
+                        follow,
+                    } => {
+                        let mut url = Client::url(&["services", service, "logs"]);
+                        url.query_pairs_mut()
+                            .append_pair("follow", &follow.to_string());
+                        url
+                    }
+                    CtlCommand::Events {
+                        service: Some(ref service),
+                    } => {
+                        let mut url = Client::url(&["events"]);
+                        url.query_pairs_mut().append_pair("service", service);
+                        url
+                    }
+                    _ => Client::url(&["events"]),
+                };
+                let mut response = client.get(url).await?;
+                let mut stdout = std::io::stdout();
+                while let Some(chunk) = response.chunk().await? {
+                    stdout.write_all(&chunk)?;
+                    stdout.flush()?;
+                }
+                return Ok(());
+            }
+        };
+
+        let body = response.text().await?;
+        if self.json {
+            println!("{}", body);
+            return Ok(());
+        }
+
+        let statuses = match self.command {
+            CtlCommand::Status { service: None } => serde_json::from_str(&body)?,
+            _ => vec![serde_json::from_str(&body)?],
+        };
+        print!("{}", table(&statuses));
+        Ok(())
+    }
+}
+
+fn local_time(time: Option<DateTime<Utc>>) -> String {
+    match time {
+        Some(t) => t
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => String::from("-"),
+    }
+}
+
+/// Formats `statuses` as a table, one service per line
+fn table(statuses: &[ServiceStatus]) -> String {
+    let rows: Vec<[String; 6]> = statuses
+        .iter()
+        .map(|s| {
+            [
+                s.name.clone(),
+                s.state.as_str().to_owned(),
+                s.pid.map_or(String::from("-"), |p| p.to_string()),
+                (s.connections + s.udp_sessions).to_string(),
+                local_time(s.last_activity),
+                local_time(s.idle_deadline),
+            ]
+        })
+        .collect();
+    let header = [
+        "SERVICE",
+        "STATE",
+        "PID",
+        "CONNECTIONS",
+        "LAST ACTIVITY",
+        "STOPS AT",
+    ];
+    columns(header, &rows)
+}
+
+/// Lines up `rows` under `header`, padding every cell to the widest one in its column
+pub fn columns<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let header = header.map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use tokio::sync::watch;
+
+    use super::{table, Client};
+    use crate::admin::{self, AdminConfig};
+    use crate::event::EventBus;
+    use crate::service::state::ServiceState;
+    use crate::service::ServiceStatus;
+
+    #[test]
+    fn formats_table() {
+        let status = ServiceStatus {
+            name: String::from("minecraft"),
+            state: ServiceState::Ready,
+            pid: Some(1234),
+            session_id: Some(1234),
+            started_at: None,
+            last_activity: None,
+            idle_deadline: None,
+            keep_awake_until: None,
+            connections: 2,
+            total_connections: 10,
+            udp_sessions: 0,
+            bytes_to_destination: 0,
+            bytes_to_client: 0,
+        };
+
+        assert_eq!(
+            table(&[status]),
+            "SERVICE    STATE  PID   CONNECTIONS  LAST ACTIVITY  STOPS AT\n\
+             minecraft  ready  1234  2            -              -\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn talks_to_admin_socket() -> anyhow::Result<()> {
+        let socket = std::env::temp_dir().join(format!("knocker-ctl-{}.sock", std::process::id()));
+        let config = AdminConfig {
+            socket: Some(socket.clone()),
+            ..AdminConfig::default()
+        };
+        let (_services, receiver) = watch::channel(BTreeMap::new());
+        admin::serve(&config, receiver, &EventBus::default()).await?;
+
+        let client = Client::new(&socket)?;
+        let statuses: Vec<ServiceStatus> =
+            client.get(Client::url(&["services"])).await?.json().await?;
+        assert!(statuses.is_empty());
+
+        let error = client
+            .post(Client::url(&["services", "web", "start"]), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no service named web");
+
+        // names are a single segment, whatever they contain
+        let error = client
+            .post(Client::url(&["services", "my web/2?#", "start"]), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no service named my web/2?#");
+
+        std::fs::remove_file(socket)?;
+        Ok(())
+    }
+}
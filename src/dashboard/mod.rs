@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, WatchStream};
+use tokio_stream::{StreamExt, StreamMap};
+
+use crate::admin::{self, ApiError, Services};
+use crate::service::state::ServiceState;
+use crate::service::{ServiceCommand, ServiceControl};
+
+/// Time between updates sent to the dashboard when no service changes state, so idle deadlines
+/// and connection counts stay fresh
+const REFRESH: Duration = Duration::from_secs(15);
+
+/// Wrong passwords a client can try in a row before it has to wait between attempts
+const FREE_ATTEMPTS: u32 = 3;
+/// Longest a client has to wait between attempts, however many wrong passwords it tried
+const MAX_LOCKOUT: Duration = Duration::from_secs(300);
+/// Time after the last wrong password when a client's attempts are forgotten
+const FORGET_ATTEMPTS: Duration = Duration::from_secs(3600);
+
+/// Web page listing the services, where anyone with the password can wake them up.
+///
+/// Unlike the admin API, it only shows what the users of the services care about.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DashboardConfig {
+    /// TCP address to serve the dashboard on
+    pub listen: SocketAddr,
+    /// Password asked for before waking a service
+    pub password: String,
+}
+
+impl DashboardConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.password.is_empty() {
+            return Err(anyhow!("dashboard needs a password"));
+        }
+        Ok(())
+    }
+}
+
+/// What the dashboard shows about a service
+#[derive(Debug, Serialize)]
+struct ServiceView {
+    name: String,
+    state: ServiceState,
+    /// How long the child usually takes to be ready, in seconds
+    cold_start: Option<f64>,
+    /// When the child should be ready, while it starts
+    ready_at: Option<DateTime<Utc>>,
+    /// When the child is stopped for being idle, if it is up
+    idle_deadline: Option<DateTime<Utc>>,
+    connections: usize,
+}
+
+impl ServiceView {
+    fn new(control: &ServiceControl) -> Self {
+        let status = control.status();
+        let cold_start = control.metrics().average_cold_start();
+        let ready_at = match (status.state, cold_start) {
+            // the state changes right before the child's pid and start time are known
+            (ServiceState::Starting, Some(cold_start)) => Some(
+                status.started_at.unwrap_or_else(Utc::now)
+                    + TimeDelta::from_std(cold_start).unwrap_or_default(),
+            ),
+            _ => None,
+        };
+        Self {
+            name: status.name,
+            state: status.state,
+            cold_start: cold_start.map(|d| d.as_secs_f64()),
+            ready_at,
+            idle_deadline: status.idle_deadline,
+            connections: status.connections + status.udp_sessions,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Wake {
+    password: String,
+}
+
+/// Wrong passwords each client tried in a row, and when it tried the last one
+#[derive(Debug, Default)]
+struct Attempts(Mutex<HashMap<IpAddr, (u32, Instant)>>);
+
+impl Attempts {
+    /// How long `client` still has to wait before trying another password
+    fn locked_out(&self, client: IpAddr) -> Option<Duration> {
+        let attempts = self.0.lock().unwrap();
+        let (failures, last) = attempts.get(&client).copied()?;
+        if failures < FREE_ATTEMPTS {
+            return None;
+        }
+        // every wrong password past the free ones doubles the wait
+        let lockout = Duration::from_secs(1u64 << (failures - FREE_ATTEMPTS).min(16));
+        let until = last + lockout.min(MAX_LOCKOUT);
+        let now = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    fn failed(&self, client: IpAddr) {
+        let now = Instant::now();
+        let mut attempts = self.0.lock().unwrap();
+        attempts.retain(|_, (_, last)| now.duration_since(*last) < FORGET_ATTEMPTS);
+        let (failures, last) = attempts.entry(client).or_insert((0, now));
+        *failures += 1;
+        *last = now;
+    }
+
+    fn succeeded(&self, client: IpAddr) {
+        self.0.lock().unwrap().remove(&client);
+    }
+}
+
+/// Binds the dashboard, so a bad address fails right away, and serves it in the background
+pub async fn serve(config: &DashboardConfig, services: Services) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .map_err(|e| anyhow!("could not bind dashboard to {}: {}", config.listen, e))?;
+    info!("Dashboard listening on {}", config.listen);
+    // wrong passwords are limited by client address
+    let app = router(services, config.password.clone())
+        .into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Dashboard stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn router(services: Services, password: String) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/services", get(list))
+        .route("/services/{name}/wake", post(wake))
+        .route("/events", get(events))
+        .with_state((services, password, Arc::default()))
+}
+
+type Dashboard = (Services, String, Arc<Attempts>);
+
+async fn index() -> Html<&'static str> {
+    Html(include_str!("index.html"))
+}
+
+fn views(services: &Services) -> Vec<ServiceView> {
+    services.borrow().values().map(ServiceView::new).collect()
+}
+
+async fn list(State((services, _, _)): State<Dashboard>) -> Json<Vec<ServiceView>> {
+    Json(views(&services))
+}
+
+async fn wake(
+    State((services, password, attempts)): State<Dashboard>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(body): Json<Wake>,
+) -> Result<Json<ServiceView>, ApiError> {
+    if let Some(wait) = attempts.locked_out(client.ip()) {
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "too many wrong passwords, try again in {}s",
+                wait.as_secs() + 1
+            ),
+        ));
+    }
+    // comparing in constant time doesn't tell how much of the password was right
+    if !bool::from(body.password.as_bytes().ct_eq(password.as_bytes())) {
+        attempts.failed(client.ip());
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "wrong password"));
+    }
+    attempts.succeeded(client.ip());
+    let service = admin::find(&services, &name)?;
+    info!("[{}] Dashboard requested a wake", name);
+    // woken like a connection would, so a crashing child still gets its start backoff
+    service
+        .send(ServiceCommand::Wake)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::CONFLICT, e))?;
+    Ok(Json(ServiceView::new(&service)))
+}
+
+/// Every service, sent again as a server-sent event whenever any of them changes state
+async fn events(State((mut services, _, _)): State<Dashboard>) -> impl IntoResponse {
+    let (sender, receiver) = mpsc::channel::<Result<Event, Infallible>>(1);
+    tokio::spawn(async move {
+        loop {
+            let mut states: StreamMap<String, _> = services
+                .borrow_and_update()
+                .iter()
+                .map(|(name, s)| (name.clone(), WatchStream::from_changes(s.state())))
+                .collect();
+
+            // the set of services only changes on reloads, their states change all the time
+            loop {
+                let event = Event::default()
+                    .event("services")
+                    .json_data(views(&services))
+                    .unwrap_or_default();
+                if sender.send(Ok(event)).await.is_err() {
+                    return;
+                }
+                select! {
+                    _ = sender.closed() => return,
+                    changed = services.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                        break;
+                    }
+                    Some(_) = states.next() => {}
+                    _ = tokio::time::sleep(REFRESH) => {}
+                }
+            }
+        }
+    });
+    Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    use axum::body::{to_bytes, Body};
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::{header, Request, StatusCode};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::router;
+    use crate::config::Config;
+    use crate::event::EventBus;
+    use crate::service::Service;
+
+    fn wake(password: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/services/web/wake")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"password":"{}"}}"#, password)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn wakes_with_password() -> anyhow::Result<()> {
+        let mut config = Config::from_toml(
+            r#"
+            [services.web]
+            listen = ["127.0.0.1:8080"]
+            destination = "127.0.0.1:8081"
+            command = "python -m http.server 8081"
+            "#,
+        )?;
+        let (service, handle) = Service::new(
+            String::from("web"),
+            config.services.remove("web").unwrap(),
+            &EventBus::default(),
+        );
+        let (_, services) =
+            watch::channel(BTreeMap::from([(String::from("web"), handle.control())]));
+        let client: SocketAddr = "192.168.1.20:50000".parse()?;
+        let app = router(services, String::from("hunter2")).layer(MockConnectInfo(client));
+
+        let page = Request::builder().uri("/").body(Body::empty())?;
+        let response = app.clone().oneshot(page).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let list = Request::builder().uri("/services").body(Body::empty())?;
+        let body = to_bytes(app.clone().oneshot(list).await?.into_body(), usize::MAX).await?;
+        let views: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(views[0]["name"], "web");
+        assert_eq!(views[0]["state"], "stopped");
+        assert!(views[0].get("pid").is_none());
+
+        let response = app.clone().oneshot(wake("letmein")).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // the right password gets through, to a service that isn't running
+        drop(service);
+        let response = app.clone().oneshot(wake("hunter2")).await?;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // guessing has to slow down after a few wrong passwords, even for the right one
+        for _ in 0..3 {
+            let response = app.clone().oneshot(wake("letmein")).await?;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = app.oneshot(wake("hunter2")).await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+}
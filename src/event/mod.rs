@@ -0,0 +1,255 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::AsyncWriteExt;
+use tokio::select;
+use tokio::sync::{broadcast, oneshot};
+
+use crate::child::ChildEvent;
+
+/// Events a subscriber can fall behind by before it starts missing some
+const CAPACITY: usize = 1024;
+
+/// Something that happened to a service, as told to everything subscribed to the [`EventBus`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Event {
+    pub service: String,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The child is being started, for a reason like `wake` or `request`
+    ServiceWaking {
+        reason: String,
+        client: Option<SocketAddr>,
+    },
+    ChildSpawned {
+        pid: u32,
+    },
+    /// The child became ready, this long after it started waking up
+    Ready {
+        #[serde(with = "seconds")]
+        after: Duration,
+    },
+    /// The child's users were warned that it stops in `before`
+    IdleWarning {
+        #[serde(with = "seconds")]
+        before: Duration,
+    },
+    /// The child is being stopped, for a reason like `idle` or `crashed`
+    Stopping {
+        reason: String,
+    },
+    /// The child is gone, with its exit code or the name of the signal that killed it
+    Exited {
+        code: Option<i32>,
+        signal: Option<String>,
+    },
+    /// A client connected, or sent its first UDP packet
+    ConnectionOpened {
+        peer: SocketAddr,
+    },
+    ConnectionClosed {
+        peer: SocketAddr,
+        bytes_to_destination: u64,
+        bytes_to_client: u64,
+    },
+}
+
+impl EventKind {
+    /// Every value of the `event` field
+    pub const NAMES: [&'static str; 8] = [
+        "service_waking",
+        "child_spawned",
+        "ready",
+        "idle_warning",
+        "stopping",
+        "exited",
+        "connection_opened",
+        "connection_closed",
+    ];
+
+    /// The name of the event, as in its `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::ServiceWaking { .. } => "service_waking",
+            EventKind::ChildSpawned { .. } => "child_spawned",
+            EventKind::Ready { .. } => "ready",
+            EventKind::IdleWarning { .. } => "idle_warning",
+            EventKind::Stopping { .. } => "stopping",
+            EventKind::Exited { .. } => "exited",
+            EventKind::ConnectionOpened { .. } => "connection_opened",
+            EventKind::ConnectionClosed { .. } => "connection_closed",
+        }
+    }
+}
+
+impl From<ChildEvent> for EventKind {
+    fn from(event: ChildEvent) -> Self {
+        let ChildEvent::Exited { code, signal } = event;
+        EventKind::Exited {
+            code,
+            signal: signal.map(|s| s.as_str().to_owned()),
+        }
+    }
+}
+
+/// Durations as a number of seconds
+mod seconds {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Events of every service the process supervises. Events that nobody is subscribed to are
+/// dropped.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    /// Receives every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn publisher(&self, service: &str) -> Publisher {
+        Publisher {
+            service: service.to_owned(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// Publishes the events of a single service
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    service: String,
+    sender: broadcast::Sender<Event>,
+}
+
+impl Publisher {
+    pub fn publish(&self, kind: EventKind) {
+        let _ = self.sender.send(Event {
+            service: self.service.clone(),
+            time: Utc::now(),
+            kind,
+        });
+    }
+
+    /// Receives every event published from now on, by any service
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// Appends every event to the file at `path` as a JSON line, until there are no more events or
+/// `shutdown` is sent.
+///
+/// The events already published when `shutdown` is sent are still written.
+pub async fn log(
+    path: PathBuf,
+    mut events: broadcast::Receiver<Event>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut file = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Could not open event log {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    loop {
+        let event = select! {
+            event = events.recv() => event,
+            _ = &mut shutdown => {
+                loop {
+                    match events.try_recv() {
+                        Ok(event) => write(&mut file, &path, &event).await,
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    }
+                }
+                return;
+            }
+        };
+        match event {
+            Ok(event) => write(&mut file, &path, &event).await,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Event log missed {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn write(file: &mut tokio::fs::File, path: &Path, event: &Event) {
+    let line = match serde_json::to_string(event) {
+        Ok(l) => l + "\n",
+        Err(_) => return,
+    };
+    // the write only happens in the background until it's flushed, which could be after the
+    // process exited
+    let written = async {
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    };
+    if let Err(e) = written.await {
+        error!("Could not write event log {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Event, EventBus, EventKind};
+
+    #[test]
+    fn serializes_events() -> anyhow::Result<()> {
+        let bus = EventBus::default();
+        let mut events = bus.subscribe();
+        bus.publisher("minecraft").publish(EventKind::Ready {
+            after: Duration::from_millis(1500),
+        });
+
+        let event = events.try_recv()?;
+        let json = serde_json::to_value(&event)?;
+        assert_eq!(json["service"], "minecraft");
+        assert_eq!(json["event"], "ready");
+        assert_eq!(event.kind.name(), "ready");
+        assert_eq!(json["after"], 1.5);
+        assert_eq!(serde_json::from_value::<Event>(json)?, event);
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::select;
+use tokio::sync::{broadcast, oneshot};
+
+use crate::event::{Event, EventKind};
+
+/// A time a service's child was awake, from when it started waking up until it exited, as written
+/// to the ledger
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Session {
+    pub service: String,
+    pub started_at: DateTime<Utc>,
+    /// Why the child was started, like `wake` or `request`
+    pub start_reason: String,
+    /// The client whose connection woke the service up
+    pub client: Option<SocketAddr>,
+    /// Seconds the child took to be ready, if it ever was
+    pub ready_after: Option<f64>,
+    pub stopped_at: DateTime<Utc>,
+    /// Why the child was stopped, like `idle`, `request` or `crashed`
+    pub stop_reason: Option<String>,
+    pub exit_code: Option<i32>,
+    /// Name of the signal that killed the child
+    pub exit_signal: Option<String>,
+    /// Bytes of the connections closed while the child was awake
+    pub bytes_to_destination: u64,
+    pub bytes_to_client: u64,
+    /// Most connections open at once while the child was awake
+    pub peak_connections: usize,
+}
+
+impl Session {
+    fn new(event: &Event, reason: &str, client: Option<SocketAddr>, open: usize) -> Self {
+        Self {
+            service: event.service.clone(),
+            started_at: event.time,
+            start_reason: reason.to_owned(),
+            client,
+            ready_after: None,
+            stopped_at: event.time,
+            stop_reason: None,
+            exit_code: None,
+            exit_signal: None,
+            bytes_to_destination: 0,
+            bytes_to_client: 0,
+            peak_connections: open,
+        }
+    }
+}
+
+/// Every session in the ledger at `path`, oldest first
+pub fn read(path: &Path) -> anyhow::Result<Vec<Session>> {
+    let ledger = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("could not read ledger {}: {}", path.display(), e))?;
+    ledger
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                anyhow!(
+                    "line {} of {} is not a session: {}",
+                    i + 1,
+                    path.display(),
+                    e
+                )
+            })
+        })
+        .collect()
+}
+
+/// Appends `session` to the ledger at `path`
+async fn append(path: &Path, session: &Session) {
+    let written = async {
+        let line = serde_json::to_string(session)? + "\n";
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        // the write only happens in the background until it's flushed, which could be after the
+        // process exited
+        file.flush().await?;
+        anyhow::Ok(())
+    };
+    if let Err(e) = written.await {
+        error!(
+            "[{}] Could not write session ledger {}: {}",
+            session.service,
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Sessions of every service, appended to the ledger as they end
+struct Ledger {
+    path: PathBuf,
+    sessions: HashMap<String, Session>,
+    /// Connections are opened before the service wakes up for them, so they're counted separately
+    open: HashMap<String, usize>,
+}
+
+impl Ledger {
+    async fn handle(&mut self, event: Event) {
+        let connections = self.open.entry(event.service.clone()).or_default();
+        match event.kind {
+            EventKind::ConnectionOpened { .. } => *connections += 1,
+            EventKind::ConnectionClosed { .. } => *connections = connections.saturating_sub(1),
+            _ => {}
+        }
+        let connections = *connections;
+
+        if let EventKind::ServiceWaking { ref reason, client } = event.kind {
+            let session = Session::new(&event, reason, client, connections);
+            // a child that couldn't be stopped has no exit status, its session ends with it
+            if let Some(previous) = self.sessions.insert(event.service.clone(), session) {
+                append(&self.path, &previous).await;
+            }
+            return;
+        }
+
+        let session = match self.sessions.get_mut(&event.service) {
+            Some(s) => s,
+            None => return,
+        };
+        session.peak_connections = session.peak_connections.max(connections);
+        match event.kind {
+            EventKind::Ready { after } => session.ready_after = Some(after.as_secs_f64()),
+            EventKind::Stopping { ref reason } => {
+                session.stop_reason = Some(reason.clone());
+                session.stopped_at = event.time;
+            }
+            EventKind::ConnectionClosed {
+                bytes_to_destination,
+                bytes_to_client,
+                ..
+            } => {
+                session.bytes_to_destination += bytes_to_destination;
+                session.bytes_to_client += bytes_to_client;
+            }
+            EventKind::Exited { code, ref signal } => {
+                session.stopped_at = event.time;
+                session.exit_code = code;
+                session.exit_signal = signal.clone();
+                if let Some(session) = self.sessions.remove(&event.service) {
+                    append(&self.path, &session).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends the sessions still going, as ending now because server knocker is exiting
+    async fn flush(&mut self) {
+        let now = Utc::now();
+        for (_, mut session) in self.sessions.drain() {
+            session.stopped_at = now;
+            session
+                .stop_reason
+                .get_or_insert_with(|| String::from("shutdown"));
+            append(&self.path, &session).await;
+        }
+    }
+}
+
+/// Appends every session to the ledger once its child exits, until there are no more events or
+/// `shutdown` is sent.
+///
+/// Sessions still going then are appended as they are, with a `shutdown` stop reason unless they
+/// were already stopping.
+pub async fn record(
+    path: PathBuf,
+    mut events: broadcast::Receiver<Event>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut ledger = Ledger {
+        path,
+        sessions: HashMap::new(),
+        open: HashMap::new(),
+    };
+
+    loop {
+        let event = select! {
+            event = events.recv() => event,
+            _ = &mut shutdown => {
+                // the events published before the shutdown still belong to their sessions
+                loop {
+                    match events.try_recv() {
+                        Ok(event) => ledger.handle(event).await,
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    }
+                }
+                break;
+            }
+        };
+        match event {
+            Ok(event) => ledger.handle(event).await,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Session ledger missed {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    ledger.flush().await;
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::{record, Session};
+    use crate::event::{EventBus, EventKind};
+
+    #[tokio::test]
+    async fn records_sessions() -> anyhow::Result<()> {
+        let ledger =
+            std::env::temp_dir().join(format!("knocker-ledger-{}.jsonl", std::process::id()));
+        let bus = EventBus::default();
+        let (shutdown, stopped) = oneshot::channel();
+        let recorder = tokio::spawn(record(ledger.clone(), bus.subscribe(), stopped));
+
+        let client = "192.168.1.20:50000".parse()?;
+        let events = bus.publisher("minecraft");
+        events.publish(EventKind::ConnectionOpened { peer: client });
+        events.publish(EventKind::ServiceWaking {
+            reason: String::from("wake"),
+            client: Some(client),
+        });
+        events.publish(EventKind::Ready {
+            after: Duration::from_secs(41),
+        });
+        events.publish(EventKind::ConnectionOpened {
+            peer: "192.168.1.21:50000".parse()?,
+        });
+        events.publish(EventKind::ConnectionClosed {
+            peer: client,
+            bytes_to_destination: 100,
+            bytes_to_client: 2000,
+        });
+        events.publish(EventKind::Stopping {
+            reason: String::from("idle"),
+        });
+        events.publish(EventKind::Exited {
+            code: None,
+            signal: Some(String::from("SIGTERM")),
+        });
+        // still going when server knocker exits
+        bus.publisher("valheim").publish(EventKind::ServiceWaking {
+            reason: String::from("launch"),
+            client: None,
+        });
+
+        let _ = shutdown.send(());
+        recorder.await?;
+        let lines = std::fs::read_to_string(&ledger)?;
+        std::fs::remove_file(&ledger)?;
+        let sessions: Vec<Session> = lines
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(sessions.len(), 2);
+
+        let session = &sessions[0];
+        assert_eq!(session.service, "minecraft");
+        assert_eq!(session.client, Some(client));
+        assert_eq!(session.ready_after, Some(41.0));
+        assert_eq!(session.stop_reason.as_deref(), Some("idle"));
+        assert_eq!(session.exit_signal.as_deref(), Some("SIGTERM"));
+        assert_eq!(session.bytes_to_client, 2000);
+        assert_eq!(session.peak_connections, 2);
+        assert!(session.stopped_at >= session.started_at);
+
+        let session = &sessions[1];
+        assert_eq!(session.service, "valheim");
+        assert_eq!(session.stop_reason.as_deref(), Some("shutdown"));
+        assert_eq!(session.exit_code, None);
+        Ok(())
+    }
+}
@@ -1,23 +1,42 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
 
 use anyhow::anyhow;
-use clap::Parser;
-use log::{debug, error, info, trace};
-use nix::sys::signal::Signal::{SIGKILL, SIGTERM};
-use tokio::select;
-use tokio::sync::{watch, Notify};
+use clap::{Parser, Subcommand};
+use log::{info, warn};
 
-use crate::child::LinuxChild;
-use crate::proxy::ProxyEvent;
-
-use self::proxy::tcp::TCPProxy;
-use self::proxy::udp::UDPProxy;
-use self::timer::ResetSignal;
+use self::admin::AdminConfig;
+use self::child::stop::{StopPlan, StopStrategy};
+use self::config::{Config, RestartConfig, RestartPolicy, ServiceConfig};
+use self::ctl::Ctl;
+use self::dashboard::DashboardConfig;
+use self::readiness::{Probe, ReadinessConfig};
+use self::report::Report;
+use self::service::hook::Hooks;
+use self::simulate::Simulate;
+use self::supervisor::Supervisor;
+use self::top::Top;
+use self::webhook::WebhooksConfig;
 
+mod admin;
+mod backup;
 mod child;
+mod config;
+mod ctl;
+mod dashboard;
+mod event;
+mod ledger;
+mod metrics;
+mod mqtt;
 mod proxy;
+mod readiness;
+mod report;
+mod service;
+mod simulate;
+mod supervisor;
 mod timer;
+mod top;
+mod webhook;
 
 #[derive(Clone, Debug, Parser)]
 /// Server Knocker runs a child application and acts like a proxy to it.
@@ -25,13 +44,27 @@ mod timer;
 /// It expects to run a child application and proxy all packets to the port that this child is listening on.
 ///
 /// After a time without receiving any packets (idle_timeout), the child is terminated.
+///
+/// Multiple services can be supervised by the same process by describing them in a `--config`
+/// file. Otherwise, the flags below describe a single service.
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Command {
-    #[arg(long)]
+    #[command(subcommand)]
+    action: Option<Action>,
+
+    #[arg(long, conflicts_with_all = ["listen", "destination", "command"])]
+    /// TOML or YAML file declaring the services to supervise
+    ///
+    /// Files ending in `.yaml` or `.yml` are read as YAML, anything else as TOML. The file is read
+    /// again when the process receives SIGHUP.
+    config: Option<PathBuf>,
+
+    #[arg(long, required_unless_present = "config")]
     /// Address for the proxy to listen on
-    listen: SocketAddr,
-    #[arg(long)]
+    listen: Option<SocketAddr>,
+    #[arg(long, required_unless_present = "config")]
     /// Destination address for the proxy
-    destination: SocketAddr,
+    destination: Option<SocketAddr>,
 
     #[arg(long, default_value_t = String::from("1h"))]
     /// Time between received packets to consider the child applicaiton idle
@@ -44,117 +77,235 @@ struct Command {
     ///
     /// Use `h` for hour, `m` for minute, `s` for second or any combination
     grace_period: String,
+    #[arg(long)]
+    /// Signals used to stop the child instead of the grace period, like
+    /// `SIGINT@0s -> SIGTERM@30s -> SIGKILL@60s`
+    ///
+    /// Each step is only sent if the child's process group is still alive.
+    stop_plan: Option<StopPlan>,
+    #[arg(long)]
+    /// Text written to the child's stdin to ask it to stop before it gets any signal, like `stop`
+    /// for a Minecraft server
+    ///
+    /// The child has the grace period to exit on its own. Config files also support stopping the
+    /// child by running a command or through RCON.
+    stop_input: Option<String>,
 
     #[arg(long, default_value_t = false)]
     /// (experimental) For TCP proxies: if set the proxy will hold off the request until the child is ready if it
     /// was down.
     ///
-    /// This is experimental because it doesn't work properly with child applications that take a
-    /// long time to become ready. It will probably need to be paired with a "readiness probe" of sorts in the future.
+    /// The request is released once the destination accepts TCP connections, or as soon as the
+    /// child is spawned for UDP.
     hold_packets: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "readiness_log")]
+    /// Only consider the child ready once its destination accepts TCP connections
+    ///
+    /// This is the default for TCP services. Config files support other kinds of readiness probes.
+    readiness_tcp: bool,
+    #[arg(long)]
+    /// Only consider the child ready once it prints a line matching this regex
+    ///
+    /// For example, `Done \(.*\)! For help` for a Minecraft server.
+    readiness_log: Option<String>,
+    #[arg(long)]
+    /// Time the child has to become ready before its start is considered failed
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    startup_timeout: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = RestartPolicy::Never)]
+    /// What to do when the child exits without being stopped for being idle
+    ///
+    /// Config files can also limit how many crashes are restarted.
+    restart: RestartPolicy,
+
+    #[arg(long)]
+    /// Unix socket to serve the admin API on, to inspect and control the services while they run
+    ///
+    /// Defaults to `$XDG_RUNTIME_DIR/server-knocker.sock`. The `--admin-*` flags override the
+    /// `admin` section of the config file.
+    admin_socket: Option<PathBuf>,
+    #[arg(long)]
+    /// TCP address to also serve the admin API on
+    admin_listen: Option<SocketAddr>,
+    #[arg(long)]
+    /// Bearer token required by the admin API on TCP
+    admin_token: Option<String>,
+
+    #[arg(long)]
+    /// TCP address to serve a web page on, listing the services and letting anyone with the
+    /// password wake them up
+    ///
+    /// The `--dashboard-*` flags override the `dashboard` section of the config file.
+    dashboard_listen: Option<SocketAddr>,
+    #[arg(long)]
+    /// Password asked by the dashboard before waking a service
+    dashboard_password: Option<String>,
+
+    #[arg(long)]
+    /// File every time a service was awake is appended to as a JSON line, with when and for whom
+    /// it woke up, why it stopped and how much it was used
+    ///
+    /// Overrides `ledger` in the config file.
+    ledger: Option<PathBuf>,
+    #[arg(long)]
+    /// File every event is appended to as a JSON line, like the admin API's event stream
+    ///
+    /// It keeps track of every connection, for the `simulate` subcommand to replay. Overrides
+    /// `event_log` in the config file.
+    event_log: Option<PathBuf>,
 
     #[arg(short = 'u', long, default_value_t = false)]
     /// Whether to use UDP instead of the default TCP for the proxy
     udp: bool,
 
-    #[arg(long)]
+    #[arg(long, required_unless_present = "config")]
     /// Command to run as a child. It's expected that it listens on the port set by `dest` and can
     /// be terminated
     ///
     /// Make sure that terminating this command also terminates the child application. If using
     /// Server Knocker with Docker, for example, make sure to not set `-d`.
-    command: String,
+    command: Option<String>,
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> anyhow::Result<()> {
-    env_logger::builder().init();
+#[derive(Clone, Debug, Subcommand)]
+enum Action {
+    /// Inspects and controls a running server knocker through its admin socket
+    Ctl(Ctl),
+    /// Shows the services of a running server knocker live, and starts or stops them with a key
+    Top(Top),
+    /// Sums up a session ledger: how long each service was awake and asleep, how often it woke up
+    /// and how long it took, per day or week
+    Report(Report),
+    /// Replays recorded activity against idle timeouts, to see how often the child would have
+    /// woken up, how long it would have been awake, and how long clients would have waited
+    Simulate(Simulate),
+}
 
-    let cmd = Command::parse();
+impl Command {
+    /// Builds the config described by the command line, either by reading the `--config` file or
+    /// by treating the flags as a config with a single service
+    fn to_config(&self) -> anyhow::Result<Config> {
+        if let Some(ref path) = self.config {
+            let mut config = Config::load(path)?;
+            self.override_config(&mut config);
+            config.validate()?;
+            return Ok(config);
+        }
 
-    let idle_timeout = parse_duration::parse(&cmd.idle_timeout)?;
-    let grace_period = parse_duration::parse(&cmd.grace_period)?;
+        let service = ServiceConfig {
+            listen: vec![self.listen.ok_or(anyhow!("--listen is required"))?],
+            destination: self
+                .destination
+                .ok_or(anyhow!("--destination is required"))?,
+            command: self
+                .command
+                .clone()
+                .ok_or(anyhow!("--command is required"))?,
+            idle_timeout: parse_duration::parse(&self.idle_timeout)?,
+            shutdown_warnings: None,
+            grace_period: parse_duration::parse(&self.grace_period)?,
+            stop: match self.stop_input {
+                Some(ref input) => StopStrategy::Stdin {
+                    input: input.clone(),
+                },
+                None => StopStrategy::Signal,
+            },
+            stop_plan: self.stop_plan.clone(),
+            hold_packets: self.hold_packets,
+            readiness: match (self.readiness_tcp, &self.readiness_log) {
+                (true, _) => Some(ReadinessConfig::tcp()),
+                (false, Some(pattern)) => Some(ReadinessConfig::new(Probe::Log {
+                    pattern: pattern.clone(),
+                })),
+                (false, None) => None,
+            },
+            startup_timeout: self
+                .startup_timeout
+                .as_deref()
+                .map(parse_duration::parse)
+                .transpose()?,
+            start_backoff: config::default_start_backoff(),
+            max_start_backoff: config::default_max_start_backoff(),
+            start_failure_response: None,
+            backup: None,
+            hooks: Hooks::default(),
+            restart: RestartConfig {
+                policy: self.restart,
+                ..RestartConfig::default()
+            },
+            udp: self.udp,
+        };
 
-    info!("Wait for connection...");
+        let mut config = Config {
+            services: [(String::from("default"), service)].into(),
+            admin: AdminConfig::default(),
+            dashboard: None,
+            webhooks: WebhooksConfig::default(),
+            ledger: None,
+            event_log: None,
+            mqtt: None,
+        };
+        self.override_config(&mut config);
+        config.validate()?;
 
-    let (network_sender, mut network_receiver) = watch::channel(proxy::ProxyEvent::Nothing);
-
-    let can_proxy_resume = Arc::new(Notify::new());
-
-    let proxy_resume_on_child_creation = can_proxy_resume.clone();
-    let process_handler = tokio::spawn(async move {
-        let mut children = vec![child::spawn_child(&cmd.command)?];
-        loop {
-            let (timer_guard, mut handle) = ResetSignal::default().run_after(idle_timeout);
-            select! {
-                _ = &mut handle => {
-                    children.drain(0..).for_each(|mut c| {
-                        debug!("Time for app expired. Terminating {} in session {:?}", c.id(), c.get_session_id());
-                        match c.kill_process_group(SIGTERM) {
-                            Ok(()) => {
-                                let _ = c.try_kill_process_group_after(grace_period, SIGKILL);
-                                info!("Child terminated");
-                                match c.wait() {
-                                    Ok(status) => {
-                                        debug!("Child exited with status {}", status);
-                                    },
-                                    Err(e) => {
-                                        error!("failed to wait on child: {}", e);
-                                    },
-                                };
-                            },
-                            Err(e) => {
-                                error!("Problem killing child: {}", e);
-                            },
-                        };
-                    });
-                }
-                _ = network_receiver.changed() => {
-                    let v = network_receiver.borrow();
-                    match *v {
-                        ProxyEvent::DestinationNotResponding => {
-                            let c = child::spawn_child(&cmd.command)?;
-                            info!("No response from destination, spawning command");
-                            debug!("Command has id {} in session {:?}", c.id(), c.get_session_id());
-                            children.push(c);
-                            proxy_resume_on_child_creation.notify_one();
-                        },
-                        ProxyEvent::UnknownError => {
-                            error!("Some unknown error occured");
-                            return Err(anyhow!("Some unknown error occured")) as anyhow::Result<()>;
-                        },
-                        ProxyEvent::GotPacket => {
-                            debug!("Got packet, restarting cooldown");
-                            timer_guard.reset();
-                        },
-                        ProxyEvent::Nothing => {
-                            trace!("Got a TCPEvent of nothing");
-                        },
-                    };
-                }
+        Ok(config)
+    }
+
+    /// Lets the `--admin-*`, `--dashboard-*`, `--ledger` and `--event-log` flags take precedence
+    /// over the config file
+    fn override_config(&self, config: &mut Config) {
+        let admin = &mut config.admin;
+        if let Some(ref socket) = self.admin_socket {
+            admin.socket = Some(socket.clone());
+        }
+        if let Some(listen) = self.admin_listen {
+            admin.listen = Some(listen);
+        }
+        if let Some(ref token) = self.admin_token {
+            admin.token = Some(token.clone());
+        }
+
+        if let Some(listen) = self.dashboard_listen {
+            config
+                .dashboard
+                .get_or_insert_with(|| DashboardConfig {
+                    listen,
+                    password: String::new(),
+                })
+                .listen = listen;
+        }
+        if let Some(ref password) = self.dashboard_password {
+            match config.dashboard {
+                Some(ref mut dashboard) => dashboard.password = password.clone(),
+                None => warn!("--dashboard-password is ignored without --dashboard-listen"),
             }
-            // cleanup to ensure pending threads are aborted
-            handle.abort();
         }
-    });
-
-    info!("Proxy starting...");
-
-    if cmd.udp {
-        UDPProxy::new(cmd.destination, cmd.listen, network_sender)
-            .start()
-            .await?;
-    } else {
-        TCPProxy::new(cmd.destination, cmd.listen, network_sender)
-            .start(if cmd.hold_packets {
-                Some(can_proxy_resume)
-            } else {
-                None
-            })
-            .await?;
+        if let Some(ref ledger) = self.ledger {
+            config.ledger = Some(ledger.clone());
+        }
+        if let Some(ref event_log) = self.event_log {
+            config.event_log = Some(event_log.clone());
+        }
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() -> anyhow::Result<()> {
+    env_logger::builder().init();
+
+    let cmd = Command::parse();
+    match cmd.action {
+        Some(Action::Ctl(ref ctl)) => return ctl.run().await,
+        Some(Action::Top(ref top)) => return top.run().await,
+        Some(Action::Report(ref report)) => return report.run(),
+        Some(Action::Simulate(ref simulate)) => return simulate.run(),
+        None => {}
     }
+    let config = cmd.to_config()?;
 
-    info!("Proxy exiting...");
+    info!("Wait for connection...");
 
-    process_handler.await?
+    Supervisor::new(cmd.config).run(config).await
 }
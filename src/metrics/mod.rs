@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+
+use crate::event::{Event, EventKind};
+use crate::service::state::ServiceState;
+use crate::service::ServiceControl;
+
+/// Upper bounds of the cold start histogram buckets, in seconds
+const COLD_START_BUCKETS: [f64; 10] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+const STATES: [ServiceState; 6] = [
+    ServiceState::Stopped,
+    ServiceState::Starting,
+    ServiceState::Ready,
+    ServiceState::Draining,
+    ServiceState::Stopping,
+    ServiceState::Failed,
+];
+
+/// What happened to a service's child since the service started, for the metrics endpoint.
+///
+/// It's recorded from the service's events and states. The proxies count their own traffic in
+/// [`crate::proxy::ProxyStats`].
+#[derive(Debug, Default)]
+pub struct ServiceMetrics {
+    inner: Mutex<Lifecycle>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Lifecycle {
+    /// Starts of the child, by reason
+    wakeups: BTreeMap<String, u64>,
+    /// Cold starts in each of the [`COLD_START_BUCKETS`], and above all of them in the last one.
+    /// Unlike Prometheus buckets, these don't include the smaller ones.
+    cold_starts: [u64; COLD_START_BUCKETS.len() + 1],
+    cold_start_total: Duration,
+    /// Time the child was running for, not counting the current run
+    awake: Duration,
+    /// When the child went from `Stopped` to `Starting`, if it's running
+    awake_since: Option<Instant>,
+    /// Exits of the child by exit code, or signal name if it was killed
+    exits: BTreeMap<String, u64>,
+}
+
+impl ServiceMetrics {
+    fn record(&self, event: &EventKind) {
+        let mut lifecycle = self.inner.lock().unwrap();
+        match event {
+            EventKind::ServiceWaking { reason, .. } => {
+                *lifecycle.wakeups.entry(reason.clone()).or_default() += 1;
+            }
+            EventKind::Ready { after } => {
+                let bucket = COLD_START_BUCKETS
+                    .iter()
+                    .position(|le| after.as_secs_f64() <= *le)
+                    .unwrap_or(COLD_START_BUCKETS.len());
+                lifecycle.cold_starts[bucket] += 1;
+                lifecycle.cold_start_total += *after;
+            }
+            EventKind::Exited { code, signal } => {
+                let key = match (code, signal) {
+                    (Some(code), _) => code.to_string(),
+                    (None, Some(signal)) => signal.clone(),
+                    (None, None) => String::from("unknown"),
+                };
+                *lifecycle.exits.entry(key).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn transition(&self, to: ServiceState, now: Instant) {
+        let mut lifecycle = self.inner.lock().unwrap();
+        match to {
+            ServiceState::Stopped | ServiceState::Failed => {
+                if let Some(since) = lifecycle.awake_since.take() {
+                    lifecycle.awake += now - since;
+                }
+            }
+            // a quick start may only be seen once the child is ready
+            _ => {
+                lifecycle.awake_since.get_or_insert(now);
+            }
+        }
+    }
+
+    /// Records the events of `service` and the time spent in every state it goes through, until
+    /// the service is gone
+    pub async fn observe(
+        &self,
+        service: &str,
+        mut state: watch::Receiver<ServiceState>,
+        mut events: broadcast::Receiver<Event>,
+    ) {
+        self.transition(*state.borrow_and_update(), Instant::now());
+        loop {
+            select! {
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    self.transition(*state.borrow_and_update(), Instant::now());
+                }
+                event = events.recv() => match event {
+                    Ok(event) if event.service == service => self.record(&event.kind),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    }
+
+    /// How long the child took to be ready, on average
+    pub fn average_cold_start(&self) -> Option<Duration> {
+        let lifecycle = self.inner.lock().unwrap();
+        let count: u64 = lifecycle.cold_starts.iter().sum();
+        (count > 0).then(|| lifecycle.cold_start_total / count as u32)
+    }
+
+    fn snapshot(&self, now: Instant) -> Lifecycle {
+        let mut lifecycle = self.inner.lock().unwrap().clone();
+        if let Some(since) = lifecycle.awake_since {
+            lifecycle.awake += now - since;
+        }
+        lifecycle
+    }
+}
+
+/// Writes one metric family in the Prometheus text format, with one sample per service
+struct Family<'a> {
+    out: &'a mut String,
+}
+
+impl<'a> Family<'a> {
+    fn new(out: &'a mut String, name: &str, kind: &str, help: &str) -> Self {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        Self { out }
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the metrics of every service in the Prometheus text format
+pub fn render(services: &[ServiceControl]) -> String {
+    let now = Instant::now();
+    let services: Vec<_> = services
+        .iter()
+        .map(|s| (s.status(), s.stats(), s.metrics().snapshot(now)))
+        .collect();
+    let mut out = String::new();
+
+    let name = "knocker_service_state";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "gauge",
+        "Whether the service is in each state",
+    );
+    for (status, _, _) in &services {
+        for state in STATES {
+            let value = u8::from(status.state == state);
+            family.sample(
+                name,
+                &[("service", &status.name), ("state", state.as_str())],
+                value,
+            );
+        }
+    }
+
+    let name = "knocker_wakeups_total";
+    let mut family = Family::new(&mut out, name, "counter", "Starts of the child, by reason");
+    for (status, _, lifecycle) in &services {
+        for (reason, count) in &lifecycle.wakeups {
+            family.sample(
+                name,
+                &[("service", &status.name), ("reason", reason)],
+                count,
+            );
+        }
+    }
+
+    let name = "knocker_cold_start_seconds";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "histogram",
+        "Time the child took to be ready after it was started",
+    );
+    for (status, _, lifecycle) in &services {
+        let mut cumulative = 0;
+        let bounds = COLD_START_BUCKETS.iter().map(|b| b.to_string());
+        for (le, count) in bounds
+            .chain(std::iter::once(String::from("+Inf")))
+            .zip(lifecycle.cold_starts)
+        {
+            cumulative += count;
+            family.sample(
+                "knocker_cold_start_seconds_bucket",
+                &[("service", &status.name), ("le", &le)],
+                cumulative,
+            );
+        }
+        let service = [("service", status.name.as_str())];
+        family.sample(
+            "knocker_cold_start_seconds_sum",
+            &service,
+            lifecycle.cold_start_total.as_secs_f64(),
+        );
+        family.sample("knocker_cold_start_seconds_count", &service, cumulative);
+    }
+
+    let name = "knocker_awake_seconds_total";
+    let mut family = Family::new(&mut out, name, "counter", "Time the child was running for");
+    for (status, _, lifecycle) in &services {
+        family.sample(
+            name,
+            &[("service", &status.name)],
+            lifecycle.awake.as_secs_f64(),
+        );
+    }
+
+    let name = "knocker_tcp_connections";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "gauge",
+        "TCP connections currently being held or piped",
+    );
+    for (status, _, _) in &services {
+        family.sample(name, &[("service", &status.name)], status.connections);
+    }
+
+    let name = "knocker_tcp_connections_total";
+    let mut family = Family::new(&mut out, name, "counter", "TCP connections accepted");
+    for (status, _, _) in &services {
+        family.sample(name, &[("service", &status.name)], status.total_connections);
+    }
+
+    let name = "knocker_udp_sessions";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "gauge",
+        "UDP clients with their own socket to the destination",
+    );
+    for (status, _, _) in &services {
+        family.sample(name, &[("service", &status.name)], status.udp_sessions);
+    }
+
+    let name = "knocker_proxied_bytes_total";
+    let mut family = Family::new(&mut out, name, "counter", "Bytes proxied, by direction");
+    for (status, stats, _) in &services {
+        for (direction, bytes) in [
+            ("to_destination", &stats.bytes_to_destination),
+            ("to_client", &stats.bytes_to_client),
+        ] {
+            family.sample(
+                name,
+                &[("service", &status.name), ("direction", direction)],
+                bytes.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    let name = "knocker_held_connections_total";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "counter",
+        "TCP connections held while the child woke up",
+    );
+    for (status, stats, _) in &services {
+        family.sample(
+            name,
+            &[("service", &status.name)],
+            stats.held_connections.load(Ordering::Relaxed),
+        );
+    }
+
+    let name = "knocker_refused_connections_total";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "counter",
+        "TCP connections closed because the destination was unavailable",
+    );
+    for (status, stats, _) in &services {
+        family.sample(
+            name,
+            &[("service", &status.name)],
+            stats.refused_connections.load(Ordering::Relaxed),
+        );
+    }
+
+    let name = "knocker_child_exits_total";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "counter",
+        "Exits of the child, by exit code or by the signal that killed it",
+    );
+    for (status, _, lifecycle) in &services {
+        for (code, count) in &lifecycle.exits {
+            family.sample(name, &[("service", &status.name), ("code", code)], count);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::ServiceMetrics;
+    use crate::event::EventKind;
+    use crate::service::state::ServiceState::*;
+
+    #[test]
+    fn records_lifecycle() {
+        let metrics = ServiceMetrics::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let waking = EventKind::ServiceWaking {
+            reason: String::from("wake"),
+            client: None,
+        };
+
+        metrics.transition(Stopped, at(0));
+        metrics.transition(Starting, at(10));
+        metrics.record(&waking);
+        metrics.transition(Ready, at(13));
+        metrics.record(&EventKind::Ready {
+            after: Duration::from_secs(3),
+        });
+        metrics.transition(Stopping, at(60));
+        metrics.record(&EventKind::Exited {
+            code: None,
+            signal: Some(String::from("SIGKILL")),
+        });
+        metrics.transition(Stopped, at(70));
+        metrics.transition(Starting, at(100));
+        metrics.record(&waking);
+
+        let lifecycle = metrics.snapshot(at(105));
+        // the current run counts as well
+        assert_eq!(lifecycle.awake, Duration::from_secs(65));
+        // 3s is in the 5s bucket
+        assert_eq!(lifecycle.cold_starts[3], 1);
+        assert_eq!(lifecycle.cold_starts.iter().sum::<u64>(), 1);
+        assert_eq!(lifecycle.cold_start_total, Duration::from_secs(3));
+        assert_eq!(metrics.average_cold_start(), Some(Duration::from_secs(3)));
+        assert_eq!(lifecycle.wakeups["wake"], 2);
+        assert_eq!(lifecycle.exits["SIGKILL"], 1);
+    }
+}
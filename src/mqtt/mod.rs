@@ -0,0 +1,423 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS, SubscribeFilter};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+use crate::admin::Services;
+use crate::config::duration;
+use crate::service::state::ServiceState;
+use crate::service::{ServiceCommand, ServiceControl};
+
+/// Publishes every service's status to an MQTT broker, announces them to Home Assistant through
+/// MQTT discovery, and starts or stops them on command.
+///
+/// Each service's status is a retained JSON message on `<topic>/<service>`, sent when its state
+/// changes and every `interval`. Publishing `ON` or `OFF` to `<topic>/<service>/set` starts or
+/// stops it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_topic")]
+    /// Prefix of every topic server knocker publishes to
+    pub topic: String,
+    #[serde(default = "default_discovery_prefix")]
+    /// Prefix Home Assistant looks for discovery messages under
+    pub discovery_prefix: String,
+    #[serde(default = "default_interval", deserialize_with = "duration")]
+    /// Time between status updates when nothing changes
+    pub interval: Duration,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    String::from("server-knocker")
+}
+
+fn default_topic() -> String {
+    String::from("server-knocker")
+}
+
+fn default_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.password.is_some() && self.username.is_none() {
+            return Err(anyhow!("MQTT password needs a username"));
+        }
+        for topic in [&self.topic, &self.discovery_prefix] {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(anyhow!("{:?} is not a valid MQTT topic prefix", topic));
+            }
+        }
+        if self.interval.is_zero() {
+            return Err(anyhow!("MQTT interval can't be 0s"));
+        }
+        Ok(())
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/availability", self.topic)
+    }
+
+    fn status_topic(&self, service: &str) -> String {
+        format!("{}/{}", self.topic, service)
+    }
+
+    fn command_topic(&self, service: &str) -> String {
+        format!("{}/{}/set", self.topic, service)
+    }
+
+    /// Home Assistant's discovery messages for the entities of `service`: whether it's awake, its
+    /// state, its connections, and a switch to start or stop it
+    fn discovery(&self, service: &str) -> Vec<(String, serde_json::Value)> {
+        let awake = "{{ 'ON' if value_json.awake else 'OFF' }}";
+        let entities = [
+            (
+                "binary_sensor",
+                "awake",
+                json!({ "name": "Awake", "device_class": "running", "value_template": awake }),
+            ),
+            (
+                "sensor",
+                "state",
+                json!({ "name": "State", "value_template": "{{ value_json.state }}" }),
+            ),
+            (
+                "sensor",
+                "connections",
+                json!({
+                    "name": "Connections",
+                    "state_class": "measurement",
+                    "value_template": "{{ value_json.connections }}",
+                }),
+            ),
+            (
+                "switch",
+                "running",
+                json!({
+                    "name": "Running",
+                    "command_topic": self.command_topic(service),
+                    "value_template": awake,
+                }),
+            ),
+        ];
+
+        entities
+            .into_iter()
+            .map(|(component, object, mut entity)| {
+                let id = format!("server_knocker_{}_{}", service, object);
+                let common = json!({
+                    "unique_id": id,
+                    "state_topic": self.status_topic(service),
+                    "availability_topic": self.availability_topic(),
+                    "device": {
+                        "identifiers": [format!("server_knocker_{}", service)],
+                        "name": service,
+                        "manufacturer": "server-knocker",
+                    },
+                });
+                if let (Some(entity), Some(common)) = (entity.as_object_mut(), common.as_object()) {
+                    entity.extend(common.clone());
+                }
+                let topic = format!(
+                    "{}/{}/server_knocker/{}_{}/config",
+                    self.discovery_prefix, component, service, object
+                );
+                (topic, entity)
+            })
+            .collect()
+    }
+
+    /// The service and command a message to a command topic is about
+    fn command<'a>(&self, topic: &'a str, payload: &str) -> Option<(&'a str, ServiceCommand)> {
+        let service = topic
+            .strip_prefix(&self.topic)?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?;
+        let command = match payload.trim().to_ascii_uppercase().as_str() {
+            "ON" | "START" => ServiceCommand::Start,
+            "OFF" | "STOP" => ServiceCommand::Stop,
+            _ => return None,
+        };
+        Some((service, command))
+    }
+}
+
+/// What the MQTT connection tells the publishing loop
+enum Incoming {
+    Connected,
+    Message { topic: String, payload: String },
+}
+
+/// Status of a service as published to its topic
+fn status(service: &ServiceControl) -> String {
+    let status = service.status();
+    let awake = !matches!(status.state, ServiceState::Stopped | ServiceState::Failed);
+    let mut status = serde_json::to_value(status).unwrap_or_default();
+    if let Some(status) = status.as_object_mut() {
+        status.insert(String::from("awake"), json!(awake));
+    }
+    status.to_string()
+}
+
+struct Publisher {
+    config: MqttConfig,
+    client: AsyncClient,
+}
+
+impl Publisher {
+    async fn publish(&self, topic: String, payload: String) {
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            error!("Could not publish to MQTT: {}", e);
+        }
+    }
+
+    async fn announce(&self, name: &str, service: &ServiceControl) {
+        for (topic, entity) in self.config.discovery(name) {
+            self.publish(topic, entity.to_string()).await;
+        }
+        self.publish(self.config.status_topic(name), status(service))
+            .await;
+    }
+
+    /// Removes a service that is gone from Home Assistant, and its retained status
+    async fn retract(&self, name: &str) {
+        for (topic, _) in self.config.discovery(name) {
+            self.publish(topic, String::new()).await;
+        }
+        self.publish(self.config.status_topic(name), String::new())
+            .await;
+    }
+}
+
+/// Keeps the services in sync with the broker, reconnecting to it whenever needed
+pub async fn run(config: MqttConfig, mut services: Services) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        config.availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(ref username) = config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    let (client, mut connection) = AsyncClient::new(options, 64);
+
+    // the connection only makes progress while it's polled, which publishing has to wait for
+    let (sender, mut incoming) = mpsc::unbounded_channel();
+    let broker = format!("{}:{}", config.host, config.port);
+    tokio::spawn(async move {
+        let mut connected = false;
+        loop {
+            let message = match connection.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}", broker);
+                    connected = true;
+                    Incoming::Connected
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => Incoming::Message {
+                    topic: p.topic,
+                    payload: String::from_utf8_lossy(&p.payload).into_owned(),
+                },
+                Ok(_) => continue,
+                Err(e) => {
+                    if std::mem::take(&mut connected) {
+                        warn!("Lost connection to MQTT broker {}: {}", broker, e);
+                    } else {
+                        warn!("Could not connect to MQTT broker {}: {}", broker, e);
+                    }
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    });
+
+    let publisher = Publisher {
+        config: config.clone(),
+        client,
+    };
+    let mut announced: BTreeSet<String> = BTreeSet::new();
+    let mut ticks = tokio::time::interval(config.interval);
+    loop {
+        let current = services.borrow_and_update().clone();
+        for name in announced.difference(&current.keys().cloned().collect()) {
+            info!("[{}] Removing service from MQTT", name);
+            publisher.retract(name).await;
+        }
+        for (name, service) in &current {
+            if !announced.contains(name) {
+                publisher.announce(name, service).await;
+            }
+        }
+        announced = current.keys().cloned().collect();
+        let mut states: StreamMap<String, _> = current
+            .iter()
+            .map(|(name, s)| (name.clone(), WatchStream::from_changes(s.state())))
+            .collect();
+
+        // the set of services only changes on reloads, their states change all the time
+        loop {
+            select! {
+                changed = services.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                Some((name, _)) = states.next() => {
+                    publisher.publish(config.status_topic(&name), status(&current[&name])).await;
+                }
+                _ = ticks.tick() => {
+                    for (name, service) in &current {
+                        publisher.publish(config.status_topic(name), status(service)).await;
+                    }
+                }
+                message = incoming.recv() => match message {
+                    Some(Incoming::Connected) => {
+                        let topics = [
+                            config.command_topic("+"),
+                            format!("{}/status", config.discovery_prefix),
+                        ];
+                        let subscribed = publisher
+                            .client
+                            .subscribe_many(topics.map(|t| SubscribeFilter::new(t, QoS::AtLeastOnce)))
+                            .await;
+                        if let Err(e) = subscribed {
+                            error!("Could not subscribe to MQTT commands: {}", e);
+                        }
+                        publisher.publish(config.availability_topic(), String::from("online")).await;
+                        for (name, service) in &current {
+                            publisher.announce(name, service).await;
+                        }
+                    }
+                    // Home Assistant forgets entities that aren't retained when it restarts
+                    Some(Incoming::Message { topic, payload })
+                        if topic == format!("{}/status", config.discovery_prefix) =>
+                    {
+                        if payload == "online" {
+                            for (name, service) in &current {
+                                publisher.announce(name, service).await;
+                            }
+                        }
+                    }
+                    Some(Incoming::Message { topic, payload }) => {
+                        let (name, command) = match config.command(&topic, &payload) {
+                            Some(c) => c,
+                            None => {
+                                warn!("Ignoring MQTT message {:?} on {}", payload, topic);
+                                continue;
+                            }
+                        };
+                        let service = match current.get(name) {
+                            Some(s) => s.clone(),
+                            None => {
+                                warn!("Ignoring MQTT command for unknown service {}", name);
+                                continue;
+                            }
+                        };
+                        info!("[{}] MQTT requested {:?}", name, command);
+                        let name = name.to_owned();
+                        // starting may take as long as the pre-start hook
+                        tokio::spawn(async move {
+                            if let Err(e) = service.send(command).await {
+                                warn!("[{}] MQTT command failed: {}", name, e);
+                            }
+                        });
+                    }
+                    None => return,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::MqttConfig;
+    use crate::service::ServiceCommand;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            host: String::from("localhost"),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: String::from("server-knocker"),
+            topic: String::from("knocker"),
+            discovery_prefix: String::from("homeassistant"),
+            interval: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn announces_entities() {
+        let config = config();
+        let discovery = config.discovery("minecraft");
+        assert_eq!(discovery.len(), 4);
+
+        let (topic, switch) = &discovery[3];
+        assert_eq!(
+            topic,
+            "homeassistant/switch/server_knocker/minecraft_running/config"
+        );
+        assert_eq!(switch["command_topic"], "knocker/minecraft/set");
+        assert_eq!(switch["state_topic"], "knocker/minecraft");
+        assert_eq!(switch["availability_topic"], "knocker/availability");
+        assert_eq!(switch["unique_id"], "server_knocker_minecraft_running");
+
+        assert_eq!(
+            config.command("knocker/minecraft/set", "ON"),
+            Some(("minecraft", ServiceCommand::Start))
+        );
+        assert_eq!(
+            config.command("knocker/minecraft/set", "off\n"),
+            Some(("minecraft", ServiceCommand::Stop))
+        );
+        assert_eq!(config.command("knocker/minecraft", "ON"), None);
+        assert_eq!(config.command("knocker/minecraft/set", "toggle"), None);
+    }
+
+    #[test]
+    fn validates_config() {
+        let mut config = config();
+        assert!(config.validate().is_ok());
+        config.interval = Duration::ZERO;
+        assert!(config.validate().is_err());
+    }
+}
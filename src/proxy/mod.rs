@@ -1,10 +1,121 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::event::{EventKind, Publisher};
+
 pub mod udp;
 pub mod tcp;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum ProxyEvent {
-    DestinationNotResponding,
+    /// A client couldn't reach the destination, so the child should be woken up
+    DestinationNotResponding {
+        client: Option<SocketAddr>,
+    },
     UnknownError,
     GotPacket,
     Nothing,
 }
+
+/// Counters shared by every proxy of a service
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+    /// TCP connections currently being held or piped
+    pub active_connections: AtomicUsize,
+    /// TCP connections accepted since the service started
+    pub total_connections: AtomicU64,
+    /// UDP clients that have their own socket to the destination
+    pub udp_sessions: AtomicUsize,
+    pub bytes_to_destination: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+    /// TCP connections that waited for the child to be ready
+    pub held_connections: AtomicU64,
+    /// TCP connections that were closed because the destination was unavailable
+    pub refused_connections: AtomicU64,
+}
+
+/// Which way bytes are proxied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToDestination,
+    ToClient,
+}
+
+/// A single client of a proxy, announced on the event bus when it comes and goes
+pub struct Session {
+    peer: SocketAddr,
+    events: Publisher,
+    to_destination: AtomicU64,
+    to_client: AtomicU64,
+}
+
+impl Session {
+    pub fn open(peer: SocketAddr, events: Publisher) -> Self {
+        events.publish(EventKind::ConnectionOpened { peer });
+        Self {
+            peer,
+            events,
+            to_destination: AtomicU64::new(0),
+            to_client: AtomicU64::new(0),
+        }
+    }
+
+    pub fn proxied(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::ToDestination => &self.to_destination,
+            Direction::ToClient => &self.to_client,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Announces that the client is gone, with everything proxied for it
+    pub fn close(&self) {
+        self.events.publish(EventKind::ConnectionClosed {
+            peer: self.peer,
+            bytes_to_destination: self.to_destination.load(Ordering::Relaxed),
+            bytes_to_client: self.to_client.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// Counts a TCP connection as active for as long as it is alive
+pub struct Connection {
+    pub stats: Arc<ProxyStats>,
+    session: Session,
+}
+
+impl Connection {
+    pub fn proxied(&self, direction: Direction, bytes: usize) {
+        self.stats.proxied(direction, bytes);
+        self.session.proxied(direction, bytes);
+    }
+}
+
+impl ProxyStats {
+    pub fn proxied(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::ToDestination => &self.bytes_to_destination,
+            Direction::ToClient => &self.bytes_to_client,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn open(self: &Arc<Self>, session: Session) -> Connection {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            stats: self.clone(),
+            session,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+        self.session.close();
+    }
+}
@@ -1,37 +1,59 @@
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use log::info;
+use log::{error, info};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket};
-use tokio::sync::watch::Sender;
-use tokio::sync::Notify;
-
-use super::ProxyEvent;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::watch::{Receiver, Sender};
+
+use super::{Connection, Direction, ProxyEvent, ProxyStats, Session};
+use crate::event::Publisher;
+use crate::service::state::ServiceState;
+
+/// How a TCP proxy holds connections while the child wakes up
+#[derive(Clone)]
+pub struct Hold {
+    /// Written to held connections before closing them, if the child fails to start
+    pub failure_response: Option<String>,
+}
 
 pub struct TCPProxy {
     destination: SocketAddr,
     listen_addr: SocketAddr,
     notification: Arc<Sender<ProxyEvent>>,
+    status: Receiver<ServiceState>,
+    stats: Arc<ProxyStats>,
+    events: Publisher,
 }
 
 impl TCPProxy {
     pub fn new(
         destination: SocketAddr,
         listen_addr: SocketAddr,
-        notification: Sender<ProxyEvent>,
+        notification: Arc<Sender<ProxyEvent>>,
+        status: Receiver<ServiceState>,
+        stats: Arc<ProxyStats>,
+        events: Publisher,
     ) -> Self {
         Self {
             destination,
             listen_addr,
-            notification: Arc::new(notification),
+            notification,
+            status,
+            stats,
+            events,
         }
     }
 
+    /// Copies everything from `reader` to `writer`. The connection stays active until both of its
+    /// pipes are done.
     async fn pipe_sockets<R, W>(
         mut reader: R,
         mut writer: W,
         notification: Arc<Sender<ProxyEvent>>,
+        connection: Arc<Connection>,
+        direction: Direction,
     ) -> anyhow::Result<()>
     where
         R: AsyncReadExt + Unpin,
@@ -49,71 +71,144 @@ impl TCPProxy {
                 break;
             }
             writer.write_all(&reader_buffer[..bytes_read]).await?;
+            connection.proxied(direction, bytes_read);
             let _ = notification.send_replace(ProxyEvent::GotPacket);
         }
 
         Ok(())
     }
 
-    pub async fn start(&self, can_resume: Option<Arc<Notify>>) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.listen_addr).await?;
+    async fn connect(destination: SocketAddr) -> std::io::Result<TcpStream> {
+        if destination.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        }?
+        .connect(destination)
+        .await
+    }
 
-        'accept_connection: loop {
-            let (input_socket, _) = listener.accept().await?;
-            info!("receiving a new connection");
+    async fn handle_connection(
+        mut input_socket: TcpStream,
+        destination: SocketAddr,
+        notification: Arc<Sender<ProxyEvent>>,
+        mut status: Receiver<ServiceState>,
+        hold: Option<Hold>,
+        connection: Connection,
+    ) -> anyhow::Result<()> {
+        // a child that is being stopped may still accept the connection, or hold on to the
+        // destination port so that a new one can't start. Either way, the connection has to wait
+        // for the old child to be gone before it can wake up a new one.
+        if *status.borrow_and_update() == ServiceState::Stopping {
+            info!("Child is stopping, queueing connection");
+            status.wait_for(|s| *s != ServiceState::Stopping).await?;
+        }
 
-            let output_socket = loop {
-                match if self.destination.is_ipv4() {
-                    TcpSocket::new_v4()
-                } else {
-                    TcpSocket::new_v6()
-                }?
-                .connect(self.destination)
-                .await
-                {
-                    Ok(s) => break Ok(s),
-                    Err(e) => {
-                        match e.kind() {
-                            std::io::ErrorKind::BrokenPipe
-                            | std::io::ErrorKind::ConnectionAborted
-                            | std::io::ErrorKind::TimedOut
-                            | std::io::ErrorKind::ConnectionReset
-                            | std::io::ErrorKind::ConnectionRefused => {
-                                let _ = self
-                                    .notification
-                                    .send_replace(ProxyEvent::DestinationNotResponding);
-                                info!("Waiting to be able to resume");
-                                if let Some(ref r) = can_resume {
-                                    r.notified().await;
-                                } else {
-                                    continue 'accept_connection;
+        let client = input_socket.peer_addr().ok();
+        let mut woke = false;
+        let output_socket = loop {
+            match Self::connect(destination).await {
+                Ok(s) => break s,
+                Err(e) => {
+                    match e.kind() {
+                        std::io::ErrorKind::BrokenPipe
+                        | std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::TimedOut
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::ConnectionRefused => {
+                            let hold = match hold {
+                                Some(ref h) => h,
+                                None => {
+                                    let _ = notification.send_replace(
+                                        ProxyEvent::DestinationNotResponding { client },
+                                    );
+                                    connection
+                                        .stats
+                                        .refused_connections
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    return Ok(());
                                 }
-                                info!("Resuming...");
-                            }
-                            _ => {
-                                info!("Something unexpected happened to the destination: {:?}", e);
-                                let _ = self.notification.send_replace(ProxyEvent::UnknownError);
-                                break (Err(e));
+                            };
+
+                            if woke || *status.borrow() == ServiceState::Failed {
+                                info!("Destination is unavailable, closing held connection");
+                                if let Some(ref response) = hold.failure_response {
+                                    input_socket.write_all(response.as_bytes()).await?;
+                                }
+                                connection
+                                    .stats
+                                    .refused_connections
+                                    .fetch_add(1, Ordering::Relaxed);
+                                return Ok(());
                             }
-                        };
-                        continue;
-                    }
-                };
-            }?;
-
-            let (input_socket_reader, input_socket_writer) = input_socket.into_split();
-            let (output_socket_reader, output_socket_writer) = output_socket.into_split();
-
-            tokio::task::spawn(Self::pipe_sockets(
-                input_socket_reader,
-                output_socket_writer,
-                self.notification.clone(),
-            ));
-            tokio::task::spawn(Self::pipe_sockets(
-                output_socket_reader,
-                input_socket_writer,
+
+                            let _ = notification
+                                .send_replace(ProxyEvent::DestinationNotResponding { client });
+                            info!("Waiting to be able to resume");
+                            connection
+                                .stats
+                                .held_connections
+                                .fetch_add(1, Ordering::Relaxed);
+                            status
+                                .wait_for(|s| s.is_up() || *s == ServiceState::Failed)
+                                .await?;
+                            woke = true;
+                            info!("Resuming...");
+                        }
+                        _ => {
+                            info!("Something unexpected happened to the destination: {:?}", e);
+                            let _ = notification.send_replace(ProxyEvent::UnknownError);
+                            return Err(e.into());
+                        }
+                    };
+                }
+            };
+        };
+
+        let (input_socket_reader, input_socket_writer) = input_socket.into_split();
+        let (output_socket_reader, output_socket_writer) = output_socket.into_split();
+
+        let connection = Arc::new(connection);
+        tokio::task::spawn(Self::pipe_sockets(
+            input_socket_reader,
+            output_socket_writer,
+            notification.clone(),
+            connection.clone(),
+            Direction::ToDestination,
+        ));
+        tokio::task::spawn(Self::pipe_sockets(
+            output_socket_reader,
+            input_socket_writer,
+            notification,
+            connection,
+            Direction::ToClient,
+        ));
+
+        Ok(())
+    }
+
+    pub async fn start(&self, hold: Option<Hold>) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(self.listen_addr).await?;
+
+        loop {
+            let (input_socket, peer) = listener.accept().await?;
+            info!("receiving a new connection");
+
+            // every connection is handled on its own, so one being held while the child wakes up
+            // doesn't keep the others from being accepted
+            let connection = Self::handle_connection(
+                input_socket,
+                self.destination,
                 self.notification.clone(),
-            ));
+                self.status.clone(),
+                hold.clone(),
+                self.stats.open(Session::open(peer, self.events.clone())),
+            );
+            tokio::task::spawn(async move {
+                if let Err(e) = connection.await {
+                    error!("error handling connection: {e}");
+                }
+            });
         }
     }
 }
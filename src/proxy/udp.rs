@@ -1,19 +1,30 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::anyhow;
-use log::{error, info};
+use log::{debug, error, info};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::{channel, Receiver};
-use tokio::sync::watch::Sender;
+use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, channel, Receiver};
+use tokio::sync::watch::{self, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use super::ProxyEvent;
+use super::{Direction, ProxyEvent, ProxyStats, Session};
+use crate::event::Publisher;
+use crate::service::state::ServiceState;
 
 pub struct UDPProxy {
     destination: SocketAddr,
     listen_addr: SocketAddr,
     notification: Arc<Sender<ProxyEvent>>,
+    status: watch::Receiver<ServiceState>,
+    stats: Arc<ProxyStats>,
+    events: Publisher,
 }
 
 // max size of an UDP packet is 65507 bytes for IPv4 and 65527 bytes for IPv6,
@@ -22,16 +33,46 @@ pub struct UDPProxy {
 // that's larger than the buffer size.
 const UDP_MAX_PACKET_SIZE: usize = 64 * 1024;
 
+/// Time without packets either way after which a client is considered gone, since UDP never says
+const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+/// Time between checks for clients that timed out
+const SESSION_SWEEP: Duration = Duration::from_secs(10);
+
+/// A client of the proxy and the task forwarding its packets. Its session is closed once it's
+/// dropped, whether it timed out, its destination socket failed or the proxy went away.
+struct Client {
+    sender: mpsc::Sender<Vec<u8>>,
+    session: Arc<Session>,
+    stats: Arc<ProxyStats>,
+    /// Last packet from or to the client
+    last_seen: Arc<Mutex<Instant>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.stats.udp_sessions.fetch_sub(1, Ordering::Relaxed);
+        self.session.close();
+    }
+}
+
 impl UDPProxy {
     pub fn new(
         destination: SocketAddr,
         listen_addr: SocketAddr,
-        notification: Sender<ProxyEvent>,
+        notification: Arc<Sender<ProxyEvent>>,
+        status: watch::Receiver<ServiceState>,
+        stats: Arc<ProxyStats>,
+        events: Publisher,
     ) -> Self {
         Self {
             destination,
             listen_addr,
-            notification: Arc::new(notification),
+            notification,
+            status,
+            stats,
+            events,
         }
     }
     async fn respond(
@@ -68,12 +109,25 @@ impl UDPProxy {
             }
         });
 
-        let mut client_map = HashMap::new();
+        let mut client_map: HashMap<String, Client> = HashMap::new();
 
         let mut buf = [0; UDP_MAX_PACKET_SIZE];
+        let mut sweep = tokio::time::interval(SESSION_SWEEP);
         loop {
             let lo = local.clone();
-            let (read_bytes, src_addr) = lo.recv_from(&mut buf).await?;
+            let (read_bytes, src_addr) = select! {
+                r = lo.recv_from(&mut buf) => r?,
+                _ = sweep.tick() => {
+                    client_map.retain(|client_id, client| {
+                        let idle = client.last_seen.lock().unwrap().elapsed() >= SESSION_TIMEOUT;
+                        if idle {
+                            debug!("Closing session of {client_id}, it timed out");
+                        }
+                        !idle
+                    });
+                    continue;
+                }
+            };
 
             let client_id = format!("{}", src_addr);
 
@@ -93,12 +147,20 @@ impl UDPProxy {
             }
 
             let i_response_sender = response_sender.clone();
-            let sender = client_map.entry(client_id.clone()).or_insert_with(|| {
+            let client = client_map.entry(client_id.clone()).or_insert_with(|| {
+                self.stats.udp_sessions.fetch_add(1, Ordering::Relaxed);
+                let session = Arc::new(Session::open(src_addr, self.events.clone()));
+                let last_seen = Arc::new(Mutex::new(Instant::now()));
                 let (client_sender, mut client_receiver) = channel::<Vec<u8>>(512);
 
                 let mut destination_listener_addr = self.listen_addr;
                 destination_listener_addr.set_port(0);
                 let destination_addr = self.destination;
+                let stats = self.stats.clone();
+                let client_session = session.clone();
+                let client_last_seen = last_seen.clone();
+                let mut status = self.status.clone();
+                let notification = self.notification.clone();
                 let b = async move {
                     let backend_listener =
                         Arc::new(UdpSocket::bind(destination_listener_addr).await?);
@@ -118,6 +180,29 @@ impl UDPProxy {
                                 .await
                                 .ok_or(anyhow!("backend client channel closed"))?;
 
+                            // packets are queued for each client while the child is stopping, so
+                            // they reach the next child instead of the one going away, and other
+                            // clients keep being served
+                            if *status.borrow_and_update() == ServiceState::Stopping {
+                                info!("Child is stopping, queueing packets from {}", src_addr);
+                                let _ = notification.send_replace(
+                                    ProxyEvent::DestinationNotResponding {
+                                        client: Some(src_addr),
+                                    },
+                                );
+                                let state = *status
+                                    .wait_for(|s| s.is_up() || *s == ServiceState::Failed)
+                                    .await?;
+                                if state == ServiceState::Failed {
+                                    info!(
+                                        "Child failed to start, dropping packets from {}",
+                                        src_addr
+                                    );
+                                    while client_receiver.try_recv().is_ok() {}
+                                    continue;
+                                }
+                            }
+
                             backend_sender.send_to(&buf[..], destination_addr).await?;
 
                             if false {
@@ -135,6 +220,9 @@ impl UDPProxy {
                         i_response_sender
                             .send((src_addr, buf[..read_bytes].to_vec()))
                             .await?;
+                        stats.proxied(Direction::ToClient, read_bytes);
+                        client_session.proxied(Direction::ToClient, read_bytes);
+                        *client_last_seen.lock().unwrap() = Instant::now();
 
                         if false {
                             break;
@@ -143,7 +231,7 @@ impl UDPProxy {
 
                     Ok(()) as anyhow::Result<()>
                 };
-                tokio::spawn(async move {
+                let task = tokio::spawn(async move {
                     match b.await {
                         Ok(_) => {}
                         Err(e) => {
@@ -152,15 +240,33 @@ impl UDPProxy {
                     }
                 });
 
-                client_sender
+                Client {
+                    sender: client_sender,
+                    session,
+                    stats: self.stats.clone(),
+                    last_seen,
+                    task,
+                }
             });
+            *client.last_seen.lock().unwrap() = Instant::now();
 
-            match sender.send(buf[..read_bytes].to_vec()).await {
-                Ok(_) => {}
+            // waiting for a client's queue would hold up every other client
+            match client.sender.try_send(buf[..read_bytes].to_vec()) {
+                Ok(_) => {
+                    self.stats.proxied(Direction::ToDestination, read_bytes);
+                    client.session.proxied(Direction::ToDestination, read_bytes);
+                }
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping packet from {client_id}, its queue is full");
+                }
                 Err(e) => {
                     error!("Could not send to {client_id}: {e}");
-                    client_map.remove(&client_id.clone());
-                    let _ = self.notification.send(ProxyEvent::DestinationNotResponding);
+                    client_map.remove(&client_id);
+                    let _ = self
+                        .notification
+                        .send(ProxyEvent::DestinationNotResponding {
+                            client: Some(src_addr),
+                        });
                 }
             };
         }
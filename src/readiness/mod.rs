@@ -0,0 +1,381 @@
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{debug, info, trace};
+use regex::Regex;
+use serde::Deserialize;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::duration;
+
+/// How to tell that a freshly spawned child is ready to receive connections
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Probe {
+    /// Ready once a TCP connection to `address` (the service destination by default) succeeds
+    Tcp { address: Option<SocketAddr> },
+    /// Ready once a GET request to `url` answers with `status`
+    Http {
+        url: String,
+        #[serde(default = "default_http_status")]
+        status: u16,
+    },
+    /// Ready once `command` exits successfully
+    Exec { command: String },
+    /// Ready once `address` (the service destination by default) answers a datagram.
+    ///
+    /// `payload` and `expect` are hex encoded, and the answer must start with `expect` if set.
+    Udp {
+        address: Option<SocketAddr>,
+        payload: String,
+        expect: Option<String>,
+    },
+    /// Ready once the child prints a line matching the `pattern` regex on stdout or stderr
+    Log { pattern: String },
+}
+
+fn default_http_status() -> u16 {
+    200
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ReadinessConfig {
+    #[serde(flatten)]
+    pub probe: Probe,
+    #[serde(default = "default_interval", deserialize_with = "duration")]
+    /// Time between two probe attempts
+    pub interval: Duration,
+    #[serde(default = "default_timeout", deserialize_with = "duration")]
+    /// Time after which a single probe attempt is considered failed
+    pub timeout: Duration,
+    #[serde(default = "default_success_threshold")]
+    /// Consecutive successful attempts needed to consider the child ready
+    pub success_threshold: u32,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_success_threshold() -> u32 {
+    1
+}
+
+impl ReadinessConfig {
+    pub fn new(probe: Probe) -> Self {
+        Self {
+            probe,
+            interval: default_interval(),
+            timeout: default_timeout(),
+            success_threshold: default_success_threshold(),
+        }
+    }
+
+    /// Plain TCP probe of the destination, with default settings
+    pub fn tcp() -> Self {
+        Self::new(Probe::Tcp { address: None })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.probe {
+            Probe::Http { ref url, .. } => {
+                reqwest::Url::parse(url)?;
+            }
+            Probe::Exec { ref command } => {
+                if shell_words::split(command)?.is_empty() {
+                    return Err(anyhow!("exec probe has an empty command"));
+                }
+            }
+            Probe::Udp {
+                ref payload,
+                ref expect,
+                ..
+            } => {
+                decode_hex(payload)?;
+                if let Some(e) = expect {
+                    decode_hex(e)?;
+                }
+            }
+            Probe::Log { ref pattern } => {
+                Regex::new(pattern)?;
+            }
+            Probe::Tcp { .. } => {}
+        }
+        if self.interval.is_zero() {
+            return Err(anyhow!("probe interval can't be 0s"));
+        }
+        Ok(())
+    }
+}
+
+impl Probe {
+    /// Runs the probe a single time, failing if the child isn't ready
+    pub async fn check(&self, destination: SocketAddr) -> anyhow::Result<()> {
+        match self {
+            Probe::Tcp { address } => {
+                TcpStream::connect(address.unwrap_or(destination)).await?;
+            }
+            Probe::Http { url, status } => {
+                let response = reqwest::get(url).await?;
+                if response.status().as_u16() != *status {
+                    return Err(anyhow!(
+                        "expected status {} but got {}",
+                        status,
+                        response.status()
+                    ));
+                }
+            }
+            Probe::Exec { command } => {
+                let words = shell_words::split(command)?;
+                let status = tokio::process::Command::new(&words[0])
+                    .args(&words[1..])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                if !status.success() {
+                    return Err(anyhow!("probe command exited with {}", status));
+                }
+            }
+            Probe::Udp {
+                address,
+                payload,
+                expect,
+            } => {
+                let address = address.unwrap_or(destination);
+                let socket = UdpSocket::bind(if address.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                })
+                .await?;
+                socket.connect(address).await?;
+                socket.send(&decode_hex(payload)?).await?;
+
+                let mut buf = [0; 64 * 1024];
+                let read_bytes = socket.recv(&mut buf).await?;
+                if let Some(e) = expect {
+                    if !buf[..read_bytes].starts_with(&decode_hex(e)?) {
+                        return Err(anyhow!("unexpected response"));
+                    }
+                }
+            }
+            Probe::Log { .. } => {
+                return Err(anyhow!(
+                    "log probes watch the child output instead of polling"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the child to be ready, failing if that takes longer than `deadline`.
+///
+/// `output` must be subscribed before the child is spawned, so no line is missed.
+pub async fn wait_ready(
+    config: &ReadinessConfig,
+    destination: SocketAddr,
+    output: broadcast::Receiver<String>,
+    deadline: Option<Duration>,
+) -> anyhow::Result<()> {
+    let ready = async {
+        match config.probe {
+            Probe::Log { ref pattern } => wait_for_line(pattern, output).await,
+            _ => {
+                poll(config, destination).await;
+                Ok(())
+            }
+        }
+    };
+
+    match deadline {
+        Some(d) => tokio::time::timeout(d, ready)
+            .await
+            .map_err(|_| anyhow!("child wasn't ready after {:?}", d))?,
+        None => ready.await,
+    }
+}
+
+async fn wait_for_line(
+    pattern: &str,
+    mut output: broadcast::Receiver<String>,
+) -> anyhow::Result<()> {
+    let pattern = Regex::new(pattern)?;
+    loop {
+        match output.recv().await {
+            Ok(line) => {
+                if pattern.is_match(&line) {
+                    info!("Child is ready, it printed: {}", line);
+                    return Ok(());
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                debug!("Missed {} lines of child output", skipped);
+            }
+            Err(RecvError::Closed) => {
+                return Err(anyhow!("child output closed before it was ready"));
+            }
+        }
+    }
+}
+
+/// Runs the probe until it succeeds `success_threshold` times in a row
+async fn poll(config: &ReadinessConfig, destination: SocketAddr) {
+    let mut successes = 0;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match tokio::time::timeout(config.timeout, config.probe.check(destination)).await {
+            Ok(Ok(())) => {
+                successes += 1;
+                trace!(
+                    "Probe succeeded ({}/{})",
+                    successes,
+                    config.success_threshold
+                );
+                if successes >= config.success_threshold {
+                    info!("Child is ready after {} probe attempts", attempts);
+                    return;
+                }
+            }
+            Ok(Err(e)) => {
+                successes = 0;
+                debug!("Probe failed: {}", e);
+            }
+            Err(_) => {
+                successes = 0;
+                debug!("Probe timed out after {:?}", config.timeout);
+            }
+        }
+        tokio::time::sleep(config.interval).await;
+    }
+}
+
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow!("hex string {} has an odd length", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio::sync::broadcast;
+
+    use super::{decode_hex, wait_ready, Probe, ReadinessConfig};
+
+    #[test]
+    fn decodes_hex() -> anyhow::Result<()> {
+        assert_eq!(decode_hex("ffFF 0a")?, vec![0xff, 0xff, 0x0a]);
+        assert!(decode_hex("fff").is_err());
+        assert!(decode_hex("zz").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_probe() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        Probe::Tcp { address: None }.check(addr).await?;
+
+        drop(listener);
+        assert!(Probe::Tcp { address: None }.check(addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_probe() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = server.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            loop {
+                let (_, peer) = server.recv_from(&mut buf).await?;
+                server.send_to(b"pong", peer).await?;
+            }
+            #[allow(unreachable_code)]
+            Ok::<(), std::io::Error>(())
+        });
+
+        let probe = Probe::Udp {
+            address: None,
+            payload: String::from("70696e67"),
+            expect: Some(String::from("706f")),
+        };
+        probe.check(addr).await?;
+
+        let wrong = Probe::Udp {
+            address: None,
+            payload: String::from("70696e67"),
+            expect: Some(String::from("00")),
+        };
+        assert!(wrong.check(addr).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validates_config() {
+        let mut config = ReadinessConfig::tcp();
+        assert!(config.validate().is_ok());
+        config.interval = Duration::ZERO;
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn waits_for_threshold() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let config = ReadinessConfig {
+            interval: Duration::from_millis(10),
+            success_threshold: 3,
+            ..ReadinessConfig::tcp()
+        };
+
+        let (_, output) = broadcast::channel(1);
+        wait_ready(&config, addr, output, Some(Duration::from_secs(1))).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waits_for_log_line() -> anyhow::Result<()> {
+        let config = ReadinessConfig::new(Probe::Log {
+            pattern: String::from(r#"Done \(\d+\.\d+s\)! For help, type "help""#),
+        });
+        let addr = "127.0.0.1:1".parse()?;
+
+        let (sender, output) = broadcast::channel(16);
+        sender.send(String::from(
+            "[Server thread/INFO]: Preparing spawn area: 97%",
+        ))?;
+        sender.send(String::from(
+            r#"[Server thread/INFO]: Done (12.3s)! For help, type "help""#,
+        ))?;
+        wait_ready(&config, addr, output, Some(Duration::from_secs(1))).await?;
+
+        let (sender, output) = broadcast::channel(16);
+        sender.send(String::from(
+            "[Server thread/INFO]: Preparing spawn area: 97%",
+        ))?;
+        let failed = wait_ready(&config, addr, output, Some(Duration::from_millis(50))).await;
+        assert!(failed.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+use crate::ctl::columns;
+use crate::ledger::{self, Session};
+
+#[derive(Clone, Debug, Args)]
+pub struct Report {
+    /// Session ledger to report on, as written with `--ledger`
+    ledger: PathBuf,
+    #[arg(long, value_enum, default_value_t = Period::Day)]
+    /// Time covered by each line of the report, in local time
+    by: Period,
+    #[arg(long)]
+    /// Only report on this service
+    service: Option<String>,
+    #[arg(long)]
+    /// CPUs a child keeps busy while it runs, to estimate the CPU-hours saved by stopping it
+    cpus: Option<f64>,
+    #[arg(long)]
+    /// GiB of memory a child holds while it runs, to estimate the memory-hours saved by stopping
+    /// it
+    memory: Option<f64>,
+    #[arg(long, default_value_t = false)]
+    /// Print each line of the report as JSON instead of a table
+    json: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Period {
+    Day,
+    /// Weeks starting on Monday
+    Week,
+}
+
+impl Period {
+    /// The first day of the period `date` is in
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Days::new(date.weekday().num_days_from_monday() as u64),
+        }
+    }
+
+    /// The first day of the period after the one starting on `start`
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => start + Days::new(1),
+            Period::Week => start + Days::new(7),
+        }
+    }
+
+    fn label(self, start: NaiveDate) -> String {
+        match self {
+            Period::Day => start.format("%Y-%m-%d").to_string(),
+            Period::Week => start.format("%G-W%V").to_string(),
+        }
+    }
+}
+
+/// What the report says about a service over a period
+#[derive(Debug, PartialEq, Serialize)]
+struct Line {
+    service: String,
+    /// The day, the ISO week, or `total` for everything in the ledger
+    period: String,
+    awake_hours: f64,
+    asleep_hours: f64,
+    cold_starts: usize,
+    /// Median seconds the child took to be ready after a cold start
+    ready_p50: Option<f64>,
+    ready_p95: Option<f64>,
+    cpu_hours_saved: Option<f64>,
+    memory_hours_saved: Option<f64>,
+}
+
+fn hours(delta: TimeDelta) -> f64 {
+    delta.num_milliseconds() as f64 / 3_600_000.0
+}
+
+/// The `quantile` of `values`, which must be sorted, by the nearest rank
+fn percentile(values: &[f64], quantile: f64) -> Option<f64> {
+    let rank = (quantile * values.len() as f64).ceil() as usize;
+    values.get(rank.max(1) - 1).copied()
+}
+
+impl Line {
+    /// Sums up the `sessions` of `service` from `from` to `to`
+    fn new(
+        service: &str,
+        period: String,
+        sessions: &[&Session],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Self {
+        let mut awake = TimeDelta::zero();
+        let mut ready = Vec::new();
+        let mut cold_starts = 0;
+        for session in sessions {
+            let overlap = session.stopped_at.min(to) - session.started_at.max(from);
+            awake += overlap.max(TimeDelta::zero());
+            if session.started_at >= from && session.started_at < to {
+                cold_starts += 1;
+                ready.extend(session.ready_after);
+            }
+        }
+        ready.sort_by(f64::total_cmp);
+
+        Self {
+            service: service.to_owned(),
+            period,
+            awake_hours: hours(awake),
+            asleep_hours: hours((to - from - awake).max(TimeDelta::zero())),
+            cold_starts,
+            ready_p50: percentile(&ready, 0.5),
+            ready_p95: percentile(&ready, 0.95),
+            cpu_hours_saved: None,
+            memory_hours_saved: None,
+        }
+    }
+}
+
+/// A line for every service and period in the time zone `tz`, up to `now`, then a total for each
+/// service. Services are only reported on from their first session, since the ledger doesn't know
+/// anything about them before.
+fn lines<Tz: TimeZone>(sessions: &[Session], by: Period, tz: &Tz, now: DateTime<Utc>) -> Vec<Line> {
+    let mut services: BTreeMap<&str, Vec<&Session>> = BTreeMap::new();
+    for session in sessions {
+        services.entry(&session.service).or_default().push(session);
+    }
+    // days that start with a daylight saving time gap start once the clocks moved forward
+    let midnight = |date: NaiveDate| {
+        (0..24)
+            .find_map(|hour| {
+                let time = NaiveTime::from_hms_opt(hour, 0, 0)?;
+                tz.from_local_datetime(&date.and_time(time)).earliest()
+            })
+            .map_or(now, |t| t.with_timezone(&Utc))
+    };
+
+    let mut lines = Vec::new();
+    for (service, sessions) in services {
+        let first = sessions.iter().map(|s| s.started_at).min().unwrap_or(now);
+        let mut start = by.start(first.with_timezone(tz).date_naive());
+        while midnight(start) < now {
+            let next = by.next(start);
+            let (from, to) = (midnight(start).max(first), midnight(next).min(now));
+            lines.push(Line::new(service, by.label(start), &sessions, from, to));
+            start = next;
+        }
+        lines.push(Line::new(
+            service,
+            String::from("total"),
+            &sessions,
+            first,
+            now,
+        ));
+    }
+    lines
+}
+
+/// Formats `lines` as a table
+fn table(lines: &[Line]) -> String {
+    let number = |value: Option<f64>| value.map_or(String::from("-"), |v| format!("{:.1}", v));
+    let rows: Vec<[String; 9]> = lines
+        .iter()
+        .map(|l| {
+            [
+                l.service.clone(),
+                l.period.clone(),
+                format!("{:.1}", l.awake_hours),
+                format!("{:.1}", l.asleep_hours),
+                l.cold_starts.to_string(),
+                number(l.ready_p50),
+                number(l.ready_p95),
+                number(l.cpu_hours_saved),
+                number(l.memory_hours_saved),
+            ]
+        })
+        .collect();
+    let header = [
+        "SERVICE",
+        "PERIOD",
+        "AWAKE H",
+        "ASLEEP H",
+        "COLD STARTS",
+        "READY P50",
+        "READY P95",
+        "CPU-H SAVED",
+        "GIB-H SAVED",
+    ];
+    columns(header, &rows)
+}
+
+impl Report {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let mut sessions = ledger::read(&self.ledger)?;
+        if let Some(ref service) = self.service {
+            sessions.retain(|s| &s.service == service);
+        }
+        if sessions.is_empty() {
+            return Err(anyhow!("no sessions to report on"));
+        }
+
+        let mut lines = lines(&sessions, self.by, &Local, Utc::now());
+        // an always-on child would use its resources while it's asleep too
+        for line in &mut lines {
+            line.cpu_hours_saved = self.cpus.map(|cpus| cpus * line.asleep_hours);
+            line.memory_hours_saved = self.memory.map(|memory| memory * line.asleep_hours);
+        }
+
+        if self.json {
+            for line in &lines {
+                println!("{}", serde_json::to_string(line)?);
+            }
+        } else {
+            print!("{}", table(&lines));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{
+        DateTime, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc,
+    };
+
+    use super::{lines, Period};
+    use crate::ledger::Session;
+
+    fn session(start: &str, stop: &str, ready_after: f64) -> Session {
+        Session {
+            service: String::from("minecraft"),
+            started_at: start.parse().unwrap(),
+            start_reason: String::from("wake"),
+            client: None,
+            ready_after: Some(ready_after),
+            stopped_at: stop.parse().unwrap(),
+            stop_reason: Some(String::from("idle")),
+            exit_code: Some(0),
+            exit_signal: None,
+            bytes_to_destination: 0,
+            bytes_to_client: 0,
+            peak_connections: 1,
+        }
+    }
+
+    /// UTC-3 until its clocks move forward an hour at midnight on 2026-10-18, like São Paulo used to
+    #[derive(Clone, Copy, Debug)]
+    struct Gap;
+
+    impl Gap {
+        fn switch() -> NaiveDateTime {
+            "2026-10-18T03:00:00".parse().unwrap()
+        }
+
+        fn offset(hours: i32) -> FixedOffset {
+            FixedOffset::west_opt(hours * 3600).unwrap()
+        }
+    }
+
+    impl TimeZone for Gap {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Gap
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let before = *local + TimeDelta::hours(3) < Gap::switch();
+            let after = *local + TimeDelta::hours(2) >= Gap::switch();
+            match (before, after) {
+                (true, _) => LocalResult::Single(Gap::offset(3)),
+                (false, true) => LocalResult::Single(Gap::offset(2)),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            match *utc < Gap::switch() {
+                true => Gap::offset(3),
+                false => Gap::offset(2),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_by_day() -> anyhow::Result<()> {
+        let sessions = [
+            session("2026-10-12T22:00:00Z", "2026-10-13T02:00:00Z", 40.0),
+            session("2026-10-13T12:00:00Z", "2026-10-13T13:00:00Z", 60.0),
+        ];
+        let now: DateTime<Utc> = "2026-10-14T00:00:00Z".parse()?;
+        let days = lines(&sessions, Period::Day, &Utc, now);
+
+        let summary: Vec<_> = days
+            .iter()
+            .map(|l| {
+                (
+                    l.period.as_str(),
+                    l.awake_hours,
+                    l.asleep_hours,
+                    l.cold_starts,
+                    l.ready_p50,
+                    l.ready_p95,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("2026-10-12", 2.0, 0.0, 1, Some(40.0), Some(40.0)),
+                ("2026-10-13", 3.0, 21.0, 1, Some(60.0), Some(60.0)),
+                ("total", 5.0, 21.0, 2, Some(40.0), Some(60.0)),
+            ]
+        );
+
+        let weeks = lines(&sessions, Period::Week, &Utc, now);
+        assert_eq!(weeks[0].period, "2026-W42");
+        assert_eq!(weeks[0].awake_hours, 5.0);
+
+        // a day without a midnight starts once the clocks moved forward, and is an hour shorter
+        let sessions = [session(
+            "2026-10-17T12:00:00Z",
+            "2026-10-17T13:00:00Z",
+            40.0,
+        )];
+        let now: DateTime<Utc> = "2026-10-20T02:00:00Z".parse()?;
+        let days: Vec<_> = lines(&sessions, Period::Day, &Gap, now)
+            .into_iter()
+            .map(|l| (l.period, l.awake_hours + l.asleep_hours))
+            .collect();
+        assert_eq!(
+            days,
+            [
+                (String::from("2026-10-17"), 15.0),
+                (String::from("2026-10-18"), 23.0),
+                (String::from("2026-10-19"), 24.0),
+                (String::from("total"), 62.0),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::config::duration;
+
+/// Commands run around the child's lifecycle
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Runs before the child is spawned, like restoring its data
+    pub pre_start: Option<Hook>,
+    /// Runs once the child is ready, before held connections are released
+    pub post_start: Option<Hook>,
+    /// Runs before the child is asked to stop
+    pub pre_stop: Option<Hook>,
+    /// Runs once the child's whole process group is gone
+    pub post_stop: Option<Hook>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub command: String,
+    #[serde(default = "default_hook_timeout", deserialize_with = "duration")]
+    /// Time after which the hook is killed and considered failed
+    pub timeout: Duration,
+    #[serde(default)]
+    pub on_failure: HookFailure,
+}
+
+fn default_hook_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// What a failing hook does to the transition it is part of
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailure {
+    /// The failure is logged, and the transition goes on
+    #[default]
+    Ignore,
+    /// The start is considered failed. Only start hooks can abort.
+    Abort,
+}
+
+/// Which hook is running, passed to it as `KNOCKER_HOOK`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookStage {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookStage::PreStart => "pre_start",
+            HookStage::PostStart => "post_start",
+            HookStage::PreStop => "pre_stop",
+            HookStage::PostStop => "post_stop",
+        })
+    }
+}
+
+/// What a hook is told about the transition through its environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookEnv {
+    /// `KNOCKER_SERVICE`
+    pub service: String,
+    /// `KNOCKER_REASON`, like `wake` or `idle`
+    pub reason: &'static str,
+    /// `KNOCKER_CLIENT`, the address of the client that woke the child up, if any
+    pub client: Option<SocketAddr>,
+}
+
+impl Hooks {
+    pub fn get(&self, stage: HookStage) -> Option<&Hook> {
+        match stage {
+            HookStage::PreStart => self.pre_start.as_ref(),
+            HookStage::PostStart => self.post_start.as_ref(),
+            HookStage::PreStop => self.pre_stop.as_ref(),
+            HookStage::PostStop => self.post_stop.as_ref(),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for stage in [
+            HookStage::PreStart,
+            HookStage::PostStart,
+            HookStage::PreStop,
+            HookStage::PostStop,
+        ] {
+            let hook = match self.get(stage) {
+                Some(h) => h,
+                None => continue,
+            };
+            if shell_words::split(&hook.command)?.is_empty() {
+                return Err(anyhow!("{} hook has an empty command", stage));
+            }
+            if hook.on_failure == HookFailure::Abort
+                && matches!(stage, HookStage::PreStop | HookStage::PostStop)
+            {
+                return Err(anyhow!("{} hook can't abort a stop", stage));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the hook for `stage` if there's one, failing only if it failed and is allowed to abort
+    pub async fn run(&self, stage: HookStage, env: &HookEnv) -> anyhow::Result<()> {
+        let hook = match self.get(stage) {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+
+        info!("[{}] Running {} hook", env.service, stage);
+        match hook.run(stage, env).await {
+            Ok(()) => {
+                debug!("[{}] {} hook succeeded", env.service, stage);
+                Ok(())
+            }
+            Err(e) if hook.on_failure == HookFailure::Abort => {
+                Err(anyhow!("{} hook failed: {}", stage, e))
+            }
+            Err(e) => {
+                warn!("[{}] Ignoring failed {} hook: {}", env.service, stage, e);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Hook {
+    async fn run(&self, stage: HookStage, env: &HookEnv) -> anyhow::Result<()> {
+        let words = shell_words::split(&self.command)?;
+        let mut command = tokio::process::Command::new(&words[0]);
+        command
+            .args(&words[1..])
+            .env("KNOCKER_SERVICE", &env.service)
+            .env("KNOCKER_HOOK", stage.to_string())
+            .env("KNOCKER_REASON", env.reason)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(client) = env.client {
+            command.env("KNOCKER_CLIENT", client.to_string());
+        }
+
+        let status = tokio::time::timeout(self.timeout, command.status())
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", self.timeout))??;
+        if !status.success() {
+            return Err(anyhow!("exited with {}", status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Hook, HookEnv, HookFailure, HookStage, Hooks};
+
+    fn env() -> HookEnv {
+        HookEnv {
+            service: String::from("web"),
+            reason: "wake",
+            client: Some("127.0.0.1:4321".parse().unwrap()),
+        }
+    }
+
+    fn hooks(command: &str, on_failure: HookFailure) -> Hooks {
+        Hooks {
+            pre_start: Some(Hook {
+                command: String::from(command),
+                timeout: Duration::from_millis(500),
+                on_failure,
+            }),
+            ..Hooks::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_environment() -> anyhow::Result<()> {
+        let check = r#"sh -c 'test "$KNOCKER_SERVICE $KNOCKER_HOOK $KNOCKER_REASON $KNOCKER_CLIENT" = "web pre_start wake 127.0.0.1:4321"'"#;
+        hooks(check, HookFailure::Abort)
+            .run(HookStage::PreStart, &env())
+            .await
+    }
+
+    #[tokio::test]
+    async fn applies_failure_policy() {
+        let failing = hooks("false", HookFailure::Abort);
+        assert!(failing.run(HookStage::PreStart, &env()).await.is_err());
+        // there's no hook for the other stages
+        assert!(failing.run(HookStage::PostStart, &env()).await.is_ok());
+
+        let ignored = hooks("sleep 5", HookFailure::Ignore);
+        assert!(ignored.run(HookStage::PreStart, &env()).await.is_ok());
+
+        let timing_out = hooks("sleep 5", HookFailure::Abort);
+        assert!(timing_out.run(HookStage::PreStart, &env()).await.is_err());
+    }
+}
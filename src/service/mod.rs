@@ -0,0 +1,1058 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, trace, warn};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep_until, Instant};
+
+use crate::child::stop::StopOutcome;
+use crate::child::{self, Child, ChildEvent, LinuxChild};
+use crate::config::{RestartPolicy, ServiceConfig};
+use crate::event::{EventBus, EventKind, Publisher};
+use crate::metrics::ServiceMetrics;
+use crate::proxy::tcp::{Hold, TCPProxy};
+use crate::proxy::udp::UDPProxy;
+use crate::proxy::{ProxyEvent, ProxyStats};
+use crate::readiness::{self, ReadinessConfig};
+use crate::timer::ResetSignal;
+
+use self::hook::{HookEnv, HookStage};
+use self::state::{ServiceState, StateMachine};
+
+pub mod hook;
+pub mod state;
+pub mod warning;
+
+/// Clients a ready child has to fail within [`UNRESPONSIVE_WINDOW`] to be considered unresponsive
+/// and restarted, so a single refused connection doesn't take it down
+const UNRESPONSIVE_FAILURES: usize = 3;
+const UNRESPONSIVE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Why the child is being started, as told to hooks
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StartReason {
+    /// The service itself was started
+    Launch,
+    /// A client couldn't reach the destination. The client isn't known for packets that were
+    /// queued while the child was stopping.
+    Wake { client: Option<SocketAddr> },
+    /// The child crashed and its restart policy starts it again
+    Restart,
+    /// Someone asked for it through the admin API
+    Request,
+}
+
+impl StartReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            StartReason::Launch => "launch",
+            StartReason::Wake { .. } => "wake",
+            StartReason::Restart => "restart",
+            StartReason::Request => "request",
+        }
+    }
+
+    fn client(self) -> Option<SocketAddr> {
+        match self {
+            StartReason::Wake { client } => client,
+            _ => None,
+        }
+    }
+}
+
+/// Why the child is being stopped, deciding what happens once it is gone
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StopReason {
+    Idle,
+    StartFailed,
+    Unresponsive,
+    /// The child exited on its own after it was ready
+    Crashed,
+    /// Someone asked for it through the admin API
+    Requested,
+    /// Server knocker is exiting
+    Shutdown,
+}
+
+impl StopReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            StopReason::Idle => "idle",
+            StopReason::StartFailed => "start_failed",
+            StopReason::Unresponsive => "unresponsive",
+            StopReason::Crashed => "crashed",
+            StopReason::Requested => "request",
+            StopReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retirement {
+    /// The service keeps running as usual
+    Active,
+    /// The service keeps proxying, and exits once its child is stopped so a new configuration can
+    /// take over
+    Replace,
+    /// The service stops accepting connections, and exits once its child is stopped
+    Remove,
+    /// The service stops accepting connections and stops its child right away, then exits
+    Shutdown,
+}
+
+/// Something asked of a running service through its [`ServiceControl`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceCommand {
+    /// Starts the child if it isn't running, even if its last start failed recently
+    Start,
+    /// Starts the child if it isn't running, like a connection would, so it's refused while the
+    /// last start failed recently
+    Wake,
+    /// Stops the child as if it was idle
+    Stop,
+    /// Counts the idle timeout from now, as if a packet was received
+    ResetIdle,
+    /// Keeps the child up for at least this long, starting it if needed
+    KeepAwake(Duration),
+}
+
+type CommandReply = oneshot::Sender<anyhow::Result<()>>;
+
+/// A start waiting for the pre-start hook before its child is spawned
+struct Preparing {
+    hook: JoinHandle<anyhow::Result<()>>,
+    reason: StartReason,
+    since: Instant,
+    /// The start command that asked for it, answered once the child is spawned
+    reply: Option<CommandReply>,
+}
+
+/// Lines of child output kept for the admin API
+const LOG_HISTORY: usize = 200;
+
+/// What the process handler last published about its child
+#[derive(Clone, Copy, Debug, Default)]
+struct Activity {
+    pid: Option<u32>,
+    session_id: Option<i32>,
+    /// When the current child was spawned
+    started: Option<Instant>,
+    /// Last packet, or when the child went up. Kept after the child stopped.
+    last_activity: Option<Instant>,
+    /// When the child is stopped for being idle, if it is up
+    idle_deadline: Option<Instant>,
+    keep_awake_until: Option<Instant>,
+}
+
+/// Snapshot of a service, as reported by the admin API
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: ServiceState,
+    pub pid: Option<u32>,
+    pub session_id: Option<i32>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub last_activity: Option<DateTime<Utc>>,
+    pub idle_deadline: Option<DateTime<Utc>>,
+    pub keep_awake_until: Option<DateTime<Utc>>,
+    /// TCP connections currently being held or piped
+    pub connections: usize,
+    /// TCP connections accepted since the service started
+    pub total_connections: u64,
+    pub udp_sessions: usize,
+    pub bytes_to_destination: u64,
+    pub bytes_to_client: u64,
+}
+
+/// A single knocked service: its proxies and the child application they wake up.
+///
+/// Every service owns its own idle timer and `ProxyEvent` channel, so services supervised by the
+/// same process don't interfere with each other.
+pub struct Service {
+    name: String,
+    config: watch::Receiver<ServiceConfig>,
+    retirement: watch::Receiver<Retirement>,
+    state: StateMachine,
+    control: Controlled,
+    stats: Arc<ProxyStats>,
+    metrics: Arc<ServiceMetrics>,
+    output: broadcast::Sender<String>,
+    history: Arc<Mutex<VecDeque<String>>>,
+}
+
+/// Controls a running [`Service`] from the outside
+pub struct ServiceHandle {
+    config: watch::Sender<ServiceConfig>,
+    retirement: watch::Sender<Retirement>,
+    control: ServiceControl,
+}
+
+/// The process handler's end of a [`ServiceControl`]
+struct Controlled {
+    commands: mpsc::UnboundedReceiver<(ServiceCommand, CommandReply)>,
+    activity: watch::Sender<Activity>,
+    events: Publisher,
+}
+
+/// Observes a running [`Service`] and sends it commands. Unlike the [`ServiceHandle`], it can be
+/// shared with anything that needs it.
+#[derive(Clone)]
+pub struct ServiceControl {
+    name: String,
+    state: watch::Receiver<ServiceState>,
+    activity: watch::Receiver<Activity>,
+    stats: Arc<ProxyStats>,
+    metrics: Arc<ServiceMetrics>,
+    commands: mpsc::UnboundedSender<(ServiceCommand, CommandReply)>,
+    output: broadcast::Sender<String>,
+    /// The last lines of child output, oldest first
+    history: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl ServiceControl {
+    pub fn status(&self) -> ServiceStatus {
+        let activity = *self.activity.borrow();
+        ServiceStatus {
+            name: self.name.clone(),
+            state: *self.state.borrow(),
+            pid: activity.pid,
+            session_id: activity.session_id,
+            started_at: activity.started.map(wall_clock),
+            last_activity: activity.last_activity.map(wall_clock),
+            idle_deadline: activity.idle_deadline.map(wall_clock),
+            keep_awake_until: activity.keep_awake_until.map(wall_clock),
+            connections: self.stats.active_connections.load(Ordering::Relaxed),
+            total_connections: self.stats.total_connections.load(Ordering::Relaxed),
+            udp_sessions: self.stats.udp_sessions.load(Ordering::Relaxed),
+            bytes_to_destination: self.stats.bytes_to_destination.load(Ordering::Relaxed),
+            bytes_to_client: self.stats.bytes_to_client.load(Ordering::Relaxed),
+        }
+    }
+
+    /// A receiver that sees every state the service goes through from now on
+    pub fn state(&self) -> watch::Receiver<ServiceState> {
+        self.state.clone()
+    }
+
+    pub fn stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+
+    pub fn metrics(&self) -> &ServiceMetrics {
+        &self.metrics
+    }
+
+    /// The last lines of child output, and a receiver for the lines that come after them
+    pub fn logs(&self) -> (Vec<String>, broadcast::Receiver<String>) {
+        // subscribing first may repeat a line, but never loses one
+        let lines = self.output.subscribe();
+        let history = self.history.lock().unwrap().iter().cloned().collect();
+        (history, lines)
+    }
+
+    /// Runs `command` on the service, returning once it was handled
+    pub async fn send(&self, command: ServiceCommand) -> anyhow::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send((command, reply))
+            .map_err(|_| anyhow!("service {} is not running", self.name))?;
+        result
+            .await
+            .map_err(|_| anyhow!("service {} is not running", self.name))?
+    }
+}
+
+/// Converts a monotonic `instant` to the wall clock time it happened, or will happen, at
+fn wall_clock(instant: Instant) -> DateTime<Utc> {
+    let now = Instant::now();
+    if instant <= now {
+        Utc::now() - TimeDelta::from_std(now - instant).unwrap_or_default()
+    } else {
+        Utc::now() + TimeDelta::from_std(instant - now).unwrap_or_default()
+    }
+}
+
+impl ServiceHandle {
+    pub fn control(&self) -> ServiceControl {
+        self.control.clone()
+    }
+
+    pub fn config(&self) -> ServiceConfig {
+        self.config.borrow().clone()
+    }
+
+    /// Applies a new configuration to the running service.
+    ///
+    /// Only fields for which [`ServiceConfig::requires_restart`] is false take effect.
+    pub fn update(&self, config: ServiceConfig) {
+        self.config.send_replace(config);
+    }
+
+    pub fn retirement(&self) -> Retirement {
+        *self.retirement.borrow()
+    }
+
+    pub fn retire(&self, retirement: Retirement) {
+        self.retirement.send_replace(retirement);
+    }
+}
+
+impl Service {
+    pub fn new(name: String, config: ServiceConfig, events: &EventBus) -> (Self, ServiceHandle) {
+        let (config_sender, config) = watch::channel(config);
+        let (retirement_sender, retirement) = watch::channel(Retirement::Active);
+        let state = StateMachine::new(name.clone());
+        let (command_sender, commands) = mpsc::unbounded_channel();
+        let (activity, activity_receiver) = watch::channel(Activity::default());
+        let stats = Arc::new(ProxyStats::default());
+        let metrics = Arc::new(ServiceMetrics::default());
+        let (output, _) = broadcast::channel(256);
+        let history = Arc::new(Mutex::new(VecDeque::with_capacity(LOG_HISTORY)));
+        let publisher = events.publisher(&name);
+
+        let control = ServiceControl {
+            name: name.clone(),
+            state: state.subscribe(),
+            activity: activity_receiver,
+            stats: stats.clone(),
+            metrics: metrics.clone(),
+            commands: command_sender,
+            output: output.clone(),
+            history: history.clone(),
+        };
+        (
+            Self {
+                name,
+                config,
+                retirement,
+                state,
+                control: Controlled {
+                    commands,
+                    activity,
+                    events: publisher,
+                },
+                stats,
+                metrics,
+                output,
+                history,
+            },
+            ServiceHandle {
+                config: config_sender,
+                retirement: retirement_sender,
+                control,
+            },
+        )
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            name,
+            config,
+            mut retirement,
+            state,
+            control,
+            stats,
+            metrics,
+            output,
+            history,
+        } = self;
+
+        // the proxies are only created once, so later updates to the config don't affect them
+        let proxy_config = config.borrow().clone();
+
+        let (network_sender, network_receiver) = watch::channel(ProxyEvent::Nothing);
+        let network_sender = Arc::new(network_sender);
+
+        let status = state.subscribe();
+        let observer = {
+            let name = name.clone();
+            let states = state.subscribe();
+            let events = control.events.subscribe();
+            tokio::spawn(async move { metrics.observe(&name, states, events).await })
+        };
+        let events = control.events.clone();
+
+        let mut lines = output.subscribe();
+        let recorder = tokio::spawn(async move {
+            loop {
+                match lines.recv().await {
+                    Ok(line) => {
+                        let mut history = history.lock().unwrap();
+                        if history.len() == LOG_HISTORY {
+                            history.pop_front();
+                        }
+                        history.push_back(line);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let mut process_handler = tokio::spawn(Self::handle_process(
+            name.clone(),
+            config,
+            retirement.clone(),
+            network_receiver,
+            state,
+            control,
+            output,
+        ));
+
+        info!("[{}] Proxy starting...", name);
+
+        let mut proxies = JoinSet::new();
+        for listen in proxy_config.listen.iter().copied() {
+            let destination = proxy_config.destination;
+            if proxy_config.udp {
+                let proxy = UDPProxy::new(
+                    destination,
+                    listen,
+                    network_sender.clone(),
+                    status.clone(),
+                    stats.clone(),
+                    events.clone(),
+                );
+                proxies.spawn(async move { proxy.start().await });
+            } else {
+                let proxy = TCPProxy::new(
+                    destination,
+                    listen,
+                    network_sender.clone(),
+                    status.clone(),
+                    stats.clone(),
+                    events.clone(),
+                );
+                let hold = if proxy_config.hold_packets {
+                    Some(Hold {
+                        failure_response: proxy_config.start_failure_response.clone(),
+                    })
+                } else {
+                    None
+                };
+                proxies.spawn(async move { proxy.start(hold).await });
+            }
+        }
+
+        // Neither the proxies nor the process handler return unless something went wrong or the
+        // service was retired, so the first task to finish takes the whole service down with it.
+        let result = loop {
+            select! {
+                r = &mut process_handler => {
+                    break r?;
+                }
+                Some(r) = proxies.join_next() => {
+                    process_handler.abort();
+                    break r?;
+                }
+                Ok(()) = retirement.changed() => {
+                    if matches!(*retirement.borrow_and_update(), Retirement::Remove | Retirement::Shutdown) {
+                        info!("[{}] Service retiring, closing listeners", name);
+                        // connections that were already accepted are piped by their own tasks and
+                        // are left to drain on their own
+                        proxies.shutdown().await;
+                    }
+                }
+            }
+        };
+
+        proxies.shutdown().await;
+        recorder.abort();
+        observer.abort();
+
+        info!("[{}] Proxy exiting...", name);
+
+        result
+    }
+
+    /// Starts waking the service up, running the pre-start hook on its own task so the service
+    /// keeps handling events meanwhile. Once it's done, the child can be [spawned](Self::spawn).
+    fn start(
+        name: &str,
+        settings: &ServiceConfig,
+        state: &StateMachine,
+        reason: StartReason,
+        events: &Publisher,
+    ) -> Preparing {
+        let since = Instant::now();
+        state.transition(ServiceState::Starting);
+        events.publish(EventKind::ServiceWaking {
+            reason: reason.as_str().to_owned(),
+            client: reason.client(),
+        });
+        let env = HookEnv {
+            service: name.to_owned(),
+            reason: reason.as_str(),
+            client: reason.client(),
+        };
+        let hooks = settings.hooks.clone();
+        Preparing {
+            hook: tokio::spawn(async move { hooks.run(HookStage::PreStart, &env).await }),
+            reason,
+            since,
+            reply: None,
+        }
+    }
+
+    /// Spawns the child once its pre-start hook is done, returning the task waiting for it to be
+    /// ready, which tells how long the whole start took.
+    ///
+    /// The post-start hook runs as part of the returned task.
+    fn spawn(
+        name: &str,
+        settings: &ServiceConfig,
+        output: &broadcast::Sender<String>,
+        state: &StateMachine,
+        child: &mut Option<Child>,
+        start: &Preparing,
+        events: &Publisher,
+    ) -> anyhow::Result<Option<JoinHandle<anyhow::Result<Duration>>>> {
+        // a child isn't ready before its destination accepts TCP connections, unless another
+        // probe was configured. UDP destinations can't be probed without knowing what they answer
+        // to, so they're ready once spawned.
+        let probe = match settings.readiness {
+            Some(ref r) => Some(r.clone()),
+            None if !settings.udp => Some(ReadinessConfig::tcp()),
+            None => None,
+        };
+
+        let lines = output.subscribe();
+        let c = child::spawn_child(&settings.command, output, settings.needs_stdin())?;
+        debug!(
+            "[{}] Command has id {} in session {:?}",
+            name,
+            c.id(),
+            c.get_session_id()
+        );
+        events.publish(EventKind::ChildSpawned { pid: c.id() });
+        *child = Some(c);
+
+        let since = start.since;
+        if probe.is_none() && settings.hooks.post_start.is_none() {
+            state.transition(ServiceState::Ready);
+            events.publish(EventKind::Ready {
+                after: since.elapsed(),
+            });
+            return Ok(None);
+        }
+
+        let destination = settings.destination;
+        let startup_timeout = settings.startup_timeout;
+        let hooks = settings.hooks.clone();
+        let env = HookEnv {
+            service: name.to_owned(),
+            reason: start.reason.as_str(),
+            client: start.reason.client(),
+        };
+        debug!("[{}] Waiting for child to be ready", name);
+        Ok(Some(tokio::spawn(async move {
+            if let Some(probe) = probe {
+                readiness::wait_ready(&probe, destination, lines, startup_timeout).await?;
+            }
+            hooks.run(HookStage::PostStart, &env).await?;
+            Ok(since.elapsed())
+        })))
+    }
+
+    /// Gives up on the start in progress, if any, so the child can be stopped
+    fn cancel_start(
+        preparing: &mut Option<Preparing>,
+        readiness: &mut Option<JoinHandle<anyhow::Result<Duration>>>,
+    ) {
+        if let Some(p) = preparing.take() {
+            p.hook.abort();
+            if let Some(sender) = p.reply {
+                let _ = sender.send(Err(anyhow!("child was stopped before it was spawned")));
+            }
+        }
+        if let Some(r) = readiness.take() {
+            r.abort();
+        }
+    }
+
+    /// Terminates the child following its stop strategy and plan, surrounded by the stop hooks.
+    ///
+    /// The returned task finishes once the whole process group is gone, so a new child can't
+    /// overlap with the old one.
+    fn stop(
+        name: &str,
+        settings: &ServiceConfig,
+        state: &StateMachine,
+        child: &mut Option<Child>,
+        reason: StopReason,
+        events: &Publisher,
+    ) -> JoinHandle<()> {
+        state.transition(ServiceState::Stopping);
+        events.publish(EventKind::Stopping {
+            reason: reason.as_str().to_owned(),
+        });
+        let child = child.take();
+        let name = name.to_owned();
+        let strategy = settings.stop.clone();
+        let grace_period = settings.grace_period;
+        let plan = settings.stop_plan();
+        let hooks = settings.hooks.clone();
+        let backup = settings.backup.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            let mut c = match child {
+                Some(c) => c,
+                None => return,
+            };
+            let env = HookEnv {
+                service: name.clone(),
+                reason: reason.as_str(),
+                client: None,
+            };
+            // there's nothing to prepare for when the child is already gone
+            if !c.has_exited() {
+                // stop hooks can't abort, so they never fail
+                let _ = hooks.run(HookStage::PreStop, &env).await;
+            }
+
+            let sid = c.get_session_id();
+            debug!(
+                "[{}] Terminating {} in session {:?} with {}",
+                name,
+                c.id(),
+                sid,
+                plan
+            );
+            let outcome = c.stop(&strategy, grace_period, &plan).await;
+            match outcome {
+                StopOutcome::Requested => {
+                    info!("[{}] Child stopped on request", name);
+                }
+                StopOutcome::Stopped(Some(step)) => {
+                    info!("[{}] Child terminated by {}", name, step);
+                }
+                StopOutcome::Stopped(None) => {
+                    debug!("[{}] Child already exited", name);
+                }
+                StopOutcome::StillAlive => {
+                    warn!("[{}] Processes in session {:?} are still alive", name, sid);
+                }
+            }
+            if let Some(status) = c.exit_status() {
+                events.publish(EventKind::from(ChildEvent::from(status)));
+            }
+
+            if let Some(backup) = backup {
+                // a child that crashed, hung, never got ready or had to be killed may have left its
+                // data half written
+                let clean = !matches!(
+                    reason,
+                    StopReason::Crashed | StopReason::StartFailed | StopReason::Unresponsive
+                ) && match outcome {
+                    StopOutcome::Stopped(Some(step)) => step.signal != Signal::SIGKILL,
+                    StopOutcome::StillAlive => false,
+                    StopOutcome::Requested | StopOutcome::Stopped(None) => true,
+                };
+                if clean {
+                    let service = name.clone();
+                    match tokio::task::spawn_blocking(move || backup.run(&service)).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => error!("[{}] Backup failed: {}", name, e),
+                        Err(e) => error!("[{}] Backup failed: {}", name, e),
+                    }
+                } else {
+                    info!("[{}] Child didn't stop cleanly, skipping backup", name);
+                }
+            }
+
+            let _ = hooks.run(HookStage::PostStop, &env).await;
+        })
+    }
+
+    /// Drives the service through its [`ServiceState`]s, making sure there's only ever a single
+    /// child: wake requests that arrive while it is starting or stopping are coalesced
+    async fn handle_process(
+        name: String,
+        mut config: watch::Receiver<ServiceConfig>,
+        mut retirement: watch::Receiver<Retirement>,
+        mut network_receiver: watch::Receiver<ProxyEvent>,
+        state: StateMachine,
+        control: Controlled,
+        output: broadcast::Sender<String>,
+    ) -> anyhow::Result<()> {
+        let Controlled {
+            mut commands,
+            activity,
+            events,
+        } = control;
+        let mut settings = config.borrow_and_update().clone();
+        let mut child: Option<Child> = None;
+        let mut preparing = Some(Self::start(
+            &name,
+            &settings,
+            &state,
+            StartReason::Launch,
+            &events,
+        ));
+        let mut readiness: Option<JoinHandle<anyhow::Result<Duration>>> = None;
+        let mut stopping: Option<(JoinHandle<()>, StopReason)> = None;
+        // a wake request arrived while the child was stopping, so it has to start again once it
+        // is gone
+        let mut wake_queued = false;
+        // the last client that tried to wake the child up
+        let mut last_client: Option<SocketAddr> = None;
+        let mut failed_starts = 0;
+        let mut backoff: Option<Instant> = None;
+        // unexpected exits within the crash window, oldest first
+        let mut crashes: VecDeque<Instant> = VecDeque::new();
+        let mut restart: Option<Instant> = None;
+        // clients the ready child failed within the unresponsive window, oldest first
+        let mut failures: VecDeque<Instant> = VecDeque::new();
+        // the idle timeout counts from the last packet, or from when the child went up
+        let mut last_activity = Instant::now();
+        // shutdown warnings already made since the last activity
+        let mut warned = 0;
+        // the child isn't considered idle before this, even without any activity
+        let mut keep_awake: Option<Instant> = None;
+        // replies to a command wait for the activity it caused to be published
+        let mut reply: Option<(CommandReply, anyhow::Result<()>)> = None;
+        loop {
+            let retired = *retirement.borrow() != Retirement::Active;
+            if retired && matches!(state.get(), ServiceState::Stopped | ServiceState::Failed) {
+                info!("[{}] Child is stopped, retiring service", name);
+                return Ok(());
+            }
+
+            if !state.get().is_up() {
+                last_activity = Instant::now();
+                warned = 0;
+            }
+            if keep_awake.is_some_and(|t| t <= Instant::now()) {
+                keep_awake = None;
+            }
+            let mut idle_deadline = last_activity + settings.idle_timeout;
+            if let Some(until) = keep_awake {
+                idle_deadline = idle_deadline.max(until);
+            }
+
+            let up = state.get().is_up();
+            activity.send_modify(|a| {
+                let pid = child.as_ref().map(|c| c.id());
+                if a.pid != pid {
+                    a.started = pid.map(|_| Instant::now());
+                }
+                a.pid = pid;
+                a.session_id = child
+                    .as_ref()
+                    .and_then(|c| c.get_session_id().ok())
+                    .map(|sid| sid.as_raw());
+                if up {
+                    a.last_activity = Some(last_activity);
+                }
+                a.idle_deadline = up.then_some(idle_deadline);
+                a.keep_awake_until = keep_awake;
+            });
+            if let Some((reply, result)) = reply.take() {
+                let _ = reply.send(result);
+            }
+
+            let (timer_guard, mut handle) = ResetSignal::default()
+                .run_after(idle_deadline.saturating_duration_since(Instant::now()));
+            let warn_before = settings
+                .shutdown_warnings
+                .as_ref()
+                .and_then(|w| w.before.get(warned).copied());
+            select! {
+                // a starting child is bound by the startup timeout instead
+                _ = &mut handle, if state.get().is_up() => {
+                    debug!("[{}] Time for app expired", name);
+                    let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::Idle, &events);
+                    stopping = Some((stop, StopReason::Idle));
+                }
+                _ = async { sleep_until(idle_deadline.checked_sub(warn_before.unwrap()).unwrap_or(last_activity)).await }, if state.get().is_up() && warn_before.is_some() => {
+                    let before = warn_before.unwrap();
+                    warned += 1;
+                    info!("[{}] Warning that the child stops in {:?}", name, before);
+                    events.publish(EventKind::IdleWarning { before });
+                    if let Some(ref warnings) = settings.shutdown_warnings {
+                        if let Err(e) = warnings.announce(child.as_mut(), before).await {
+                            warn!("[{}] Failed to make shutdown warning: {}", name, e);
+                        }
+                    }
+                }
+                r = async { (&mut preparing.as_mut().unwrap().hook).await }, if preparing.is_some() => {
+                    let start = preparing.take().unwrap();
+                    let spawned = r
+                        .map_err(anyhow::Error::from)
+                        .and_then(|r| r)
+                        .and_then(|()| Self::spawn(&name, &settings, &output, &state, &mut child, &start, &events));
+                    let result = match spawned {
+                        Ok(r) => {
+                            readiness = r;
+                            Ok(())
+                        }
+                        Err(e) => {
+                            // failing like a child that never got ready gives it the usual start
+                            // backoff, instead of taking the whole service down
+                            error!("[{}] StartFailed: {}", name, e);
+                            let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::StartFailed, &events);
+                            stopping = Some((stop, StopReason::StartFailed));
+                            Err(anyhow!("child failed to start: {}", e))
+                        }
+                    };
+                    if let Some(sender) = start.reply {
+                        reply = Some((sender, result));
+                    }
+                }
+                r = async { readiness.as_mut().unwrap().await }, if readiness.is_some() => {
+                    readiness = None;
+                    match r.map_err(anyhow::Error::from).and_then(|r| r) {
+                        Ok(after) => {
+                            info!("[{}] Child is ready", name);
+                            failed_starts = 0;
+                            failures.clear();
+                            state.transition(if retired { ServiceState::Draining } else { ServiceState::Ready });
+                            events.publish(EventKind::Ready { after });
+                        }
+                        Err(e) => {
+                            error!("[{}] StartFailed: {}", name, e);
+                            let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::StartFailed, &events);
+                            stopping = Some((stop, StopReason::StartFailed));
+                        }
+                    }
+                }
+                r = async { child.as_mut().unwrap().wait().await }, if child.is_some() => {
+                    match r {
+                        Ok(status) => warn!("[{}] Child exited unexpectedly: {:?}", name, ChildEvent::from(status)),
+                        // the child can't be waited on anymore, so it's as good as gone
+                        Err(e) => error!("[{}] Could not wait for child, treating it as crashed: {}", name, e),
+                    }
+                    // a child that never got ready failed to start, so it gets the start backoff
+                    // and held connections are released
+                    let reason = match readiness.take() {
+                        Some(r) => {
+                            r.abort();
+                            StopReason::StartFailed
+                        }
+                        None => StopReason::Crashed,
+                    };
+                    // whatever is left of its process group is cleaned up like any other stop
+                    let stop = Self::stop(&name, &settings, &state, &mut child, reason, &events);
+                    stopping = Some((stop, reason));
+                }
+                r = async { (&mut stopping.as_mut().unwrap().0).await }, if stopping.is_some() => {
+                    let (_, reason) = stopping.take().unwrap();
+                    if let Err(e) = r {
+                        error!("[{}] Failed to stop child: {}", name, e);
+                    }
+                    debug!("[{}] Child stopped, reason: {:?}", name, reason);
+
+                    if reason == StopReason::StartFailed {
+                        failed_starts += 1;
+                        let delay = settings.start_backoff(failed_starts);
+                        info!("[{}] Refusing to start again for {:?}", name, delay);
+                        backoff = Some(Instant::now() + delay);
+                        wake_queued = false;
+                        state.transition(ServiceState::Failed);
+                    } else if reason == StopReason::Crashed {
+                        let now = Instant::now();
+                        crashes.push_back(now);
+                        let window = settings.restart.crash_window;
+                        crashes.retain(|t| now.duration_since(*t) <= window);
+                        state.transition(ServiceState::Stopped);
+
+                        match settings.restart.delay(crashes.len() as u32) {
+                            Some(delay) => {
+                                info!("[{}] Restarting child in {:?}", name, delay);
+                                restart = Some(now + delay);
+                            }
+                            None if settings.restart.policy == RestartPolicy::Never => {
+                                info!("[{}] Leaving child stopped until the next connection", name);
+                            }
+                            None => {
+                                error!(
+                                    "[{}] Child crashed {} times in {:?}, not restarting it until the next connection",
+                                    name, crashes.len(), settings.restart.crash_window
+                                );
+                            }
+                        }
+                    } else {
+                        state.transition(ServiceState::Stopped);
+                    }
+
+                    if std::mem::take(&mut wake_queued) && !retired {
+                        info!("[{}] Starting child for connections that arrived while it was stopping", name);
+                        preparing = Some(Self::start(&name, &settings, &state, StartReason::Wake { client: last_client }, &events));
+                    }
+                }
+                _ = async { sleep_until(restart.unwrap()).await }, if restart.is_some() => {
+                    restart = None;
+                    // a connection may have woken the child up in the meantime
+                    if state.get() == ServiceState::Stopped && !retired {
+                        info!("[{}] Restarting crashed child", name);
+                        preparing = Some(Self::start(&name, &settings, &state, StartReason::Restart, &events));
+                    }
+                }
+                _ = async { sleep_until(backoff.unwrap()).await }, if backoff.is_some() => {
+                    backoff = None;
+                    info!("[{}] Start backoff elapsed", name);
+                    state.transition(ServiceState::Stopped);
+                }
+                Some((command, sender)) = commands.recv() => {
+                    debug!("[{}] Got command {:?}", name, command);
+                    let mut sender = Some(sender);
+                    let mut result = Ok(());
+                    match command {
+                        ServiceCommand::Start | ServiceCommand::Wake | ServiceCommand::KeepAwake(_) => {
+                            if let ServiceCommand::KeepAwake(duration) = command {
+                                info!("[{}] Keeping child awake for {:?}", name, duration);
+                                keep_awake = Some(Instant::now() + duration);
+                                warned = 0;
+                            }
+                            match state.get() {
+                                ServiceState::Stopped | ServiceState::Failed if retired => {
+                                    result = Err(anyhow!("service is being retired"));
+                                }
+                                ServiceState::Failed if command == ServiceCommand::Wake => {
+                                    let wait = backoff.map_or(Duration::ZERO, |b| b.saturating_duration_since(Instant::now()));
+                                    result = Err(anyhow!("the last start failed, try again in {}s", wait.as_secs() + 1));
+                                }
+                                ServiceState::Stopped | ServiceState::Failed => {
+                                    if state.get() == ServiceState::Failed {
+                                        info!("[{}] Ignoring start backoff on request", name);
+                                        backoff = None;
+                                        state.transition(ServiceState::Stopped);
+                                    }
+                                    info!("[{}] Starting child on request", name);
+                                    restart = None;
+                                    let mut start = Self::start(&name, &settings, &state, StartReason::Request, &events);
+                                    // the command is answered once the child is spawned
+                                    start.reply = sender.take();
+                                    preparing = Some(start);
+                                }
+                                ServiceState::Stopping => {
+                                    info!("[{}] Starting again once the child is stopped", name);
+                                    wake_queued = true;
+                                }
+                                ServiceState::Starting | ServiceState::Ready | ServiceState::Draining => {}
+                            }
+                        }
+                        ServiceCommand::Stop => {
+                            keep_awake = None;
+                            restart = None;
+                            wake_queued = false;
+                            if matches!(state.get(), ServiceState::Starting | ServiceState::Ready | ServiceState::Draining) {
+                                info!("[{}] Stopping child on request", name);
+                                Self::cancel_start(&mut preparing, &mut readiness);
+                                let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::Requested, &events);
+                                stopping = Some((stop, StopReason::Requested));
+                            }
+                        }
+                        ServiceCommand::ResetIdle => {
+                            if up {
+                                info!("[{}] Idle timer reset on request", name);
+                                last_activity = Instant::now();
+                                warned = 0;
+                            } else {
+                                result = Err(anyhow!("child is not running"));
+                            }
+                        }
+                    }
+                    if let Some(sender) = sender {
+                        reply = Some((sender, result));
+                    }
+                }
+                Ok(()) = config.changed() => {
+                    // the idle timer is recreated with the new timeout right below, still counting
+                    // from the last activity
+                    settings = config.borrow_and_update().clone();
+                    info!("[{}] Configuration reloaded", name);
+                }
+                Ok(()) = retirement.changed() => {
+                    let retirement = *retirement.borrow_and_update();
+                    debug!("[{}] Service retirement changed to {:?}", name, retirement);
+                    match (state.get(), retirement) {
+                        (ServiceState::Ready, Retirement::Replace | Retirement::Remove) => {
+                            state.transition(ServiceState::Draining);
+                        },
+                        (ServiceState::Draining, Retirement::Active) => {
+                            state.transition(ServiceState::Ready);
+                        },
+                        (ServiceState::Starting | ServiceState::Ready | ServiceState::Draining, Retirement::Shutdown) => {
+                            info!("[{}] Shutting down, stopping child", name);
+                            Self::cancel_start(&mut preparing, &mut readiness);
+                            let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::Shutdown, &events);
+                            stopping = Some((stop, StopReason::Shutdown));
+                        },
+                        _ => {},
+                    }
+                }
+                _ = network_receiver.changed() => {
+                    let event = *network_receiver.borrow();
+                    match event {
+                        ProxyEvent::DestinationNotResponding { client } => {
+                            last_client = client;
+                            match state.get() {
+                                ServiceState::Starting => {
+                                    debug!("[{}] No response from destination, but the child is already starting", name);
+                                },
+                                ServiceState::Stopping => {
+                                    info!("[{}] No response from destination, starting again once the child is stopped", name);
+                                    wake_queued = true;
+                                },
+                                ServiceState::Failed => {
+                                    debug!("[{}] No response from destination, but the last start failed recently", name);
+                                },
+                                ServiceState::Ready | ServiceState::Draining => {
+                                    let now = Instant::now();
+                                    failures.push_back(now);
+                                    failures.retain(|t| now.duration_since(*t) <= UNRESPONSIVE_WINDOW);
+                                    if failures.len() < UNRESPONSIVE_FAILURES {
+                                        debug!("[{}] No response from destination ({} in {:?})", name, failures.len(), UNRESPONSIVE_WINDOW);
+                                    } else if state.get() == ServiceState::Draining {
+                                        info!("[{}] Destination is unresponsive while draining, stopping child", name);
+                                        failures.clear();
+                                        let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::Unresponsive, &events);
+                                        stopping = Some((stop, StopReason::Unresponsive));
+                                    } else {
+                                        info!("[{}] Destination is unresponsive, restarting child", name);
+                                        failures.clear();
+                                        let stop = Self::stop(&name, &settings, &state, &mut child, StopReason::Unresponsive, &events);
+                                        stopping = Some((stop, StopReason::Unresponsive));
+                                        wake_queued = true;
+                                    }
+                                },
+                                ServiceState::Stopped => {
+                                    info!("[{}] No response from destination, spawning command", name);
+                                    preparing = Some(Self::start(&name, &settings, &state, StartReason::Wake { client }, &events));
+                                },
+                            }
+                        },
+                        ProxyEvent::UnknownError => {
+                            error!("[{}] Some unknown error occured", name);
+                            return Err(anyhow!("Some unknown error occured"));
+                        },
+                        ProxyEvent::GotPacket => {
+                            debug!("[{}] Got packet, restarting cooldown", name);
+                            last_activity = Instant::now();
+                            if warned > 0 {
+                                info!("[{}] Activity after a shutdown warning, keeping the child up", name);
+                                warned = 0;
+                            }
+                            timer_guard.reset();
+                        },
+                        ProxyEvent::Nothing => {
+                            trace!("[{}] Got a TCPEvent of nothing", name);
+                        },
+                    };
+                }
+            }
+            // cleanup to ensure pending threads are aborted
+            handle.abort();
+        }
+    }
+}
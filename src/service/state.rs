@@ -0,0 +1,143 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Lifecycle of a service, as driven by its process handler
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    /// No child is running
+    Stopped,
+    /// The child was spawned and isn't ready yet
+    Starting,
+    /// The child is ready to receive connections
+    Ready,
+    /// The child is ready, but the service was retired and exits once the child stops
+    Draining,
+    /// The child is being terminated
+    Stopping,
+    /// The last start failed. Wake requests are refused until the start backoff elapses and the
+    /// service goes back to `Stopped`.
+    Failed,
+}
+
+impl ServiceState {
+    pub fn can_transition_to(self, to: ServiceState) -> bool {
+        use ServiceState::*;
+
+        matches!(
+            (self, to),
+            (Stopped, Starting)
+                | (Starting, Ready)
+                | (Starting, Draining)
+                | (Starting, Stopping)
+                | (Ready, Draining)
+                | (Ready, Stopping)
+                | (Draining, Ready)
+                | (Draining, Stopping)
+                | (Stopping, Stopped)
+                | (Stopping, Failed)
+                | (Failed, Stopped)
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServiceState::Stopped => "stopped",
+            ServiceState::Starting => "starting",
+            ServiceState::Ready => "ready",
+            ServiceState::Draining => "draining",
+            ServiceState::Stopping => "stopping",
+            ServiceState::Failed => "failed",
+        }
+    }
+
+    /// Whether connections to the destination are expected to succeed
+    pub fn is_up(self) -> bool {
+        matches!(self, ServiceState::Ready | ServiceState::Draining)
+    }
+}
+
+/// Owns the state of a service, logging every transition and letting anyone observe it
+pub struct StateMachine {
+    name: String,
+    sender: watch::Sender<ServiceState>,
+}
+
+impl StateMachine {
+    pub fn new(name: String) -> Self {
+        let (sender, _) = watch::channel(ServiceState::Stopped);
+        Self { name, sender }
+    }
+
+    pub fn get(&self) -> ServiceState {
+        *self.sender.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ServiceState> {
+        self.sender.subscribe()
+    }
+
+    pub fn transition(&self, to: ServiceState) {
+        let from = self.get();
+        if from == to {
+            return;
+        }
+
+        if from.can_transition_to(to) {
+            info!("[{}] {:?} -> {:?}", self.name, from, to);
+        } else {
+            warn!(
+                "[{}] Unexpected transition {:?} -> {:?}",
+                self.name, from, to
+            );
+        }
+        self.sender.send_replace(to);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServiceState::*;
+    use super::StateMachine;
+
+    #[test]
+    fn wake_and_sleep_cycle() {
+        let cycle = [Stopped, Starting, Ready, Stopping, Stopped];
+        for pair in cycle.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn failed_start_cycle() {
+        let cycle = [Stopped, Starting, Stopping, Failed, Stopped];
+        for pair in cycle.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn rejects_skipping_stopping() {
+        assert!(!Ready.can_transition_to(Stopped));
+        assert!(!Starting.can_transition_to(Stopped));
+        assert!(!Failed.can_transition_to(Starting));
+        assert!(!Stopped.can_transition_to(Ready));
+    }
+
+    #[tokio::test]
+    async fn transitions_are_observable() -> anyhow::Result<()> {
+        let state = StateMachine::new(String::from("test"));
+        let mut observer = state.subscribe();
+        assert_eq!(*observer.borrow_and_update(), Stopped);
+
+        state.transition(Starting);
+        observer.changed().await?;
+        assert_eq!(*observer.borrow_and_update(), Starting);
+
+        // transitioning to the current state is a no-op
+        state.transition(Starting);
+        assert!(!observer.has_changed()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Deserializer};
+
+use crate::child::Child;
+
+/// Longest a warning waits for the child to read it from its stdin
+const STDIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announcements made before an idle child is stopped, so its users know it's going away.
+///
+/// Any activity after a warning cancels the shutdown, and the schedule starts over.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ShutdownWarnings {
+    #[serde(flatten)]
+    pub action: WarningAction,
+    #[serde(deserialize_with = "schedule")]
+    /// How long before the idle timeout each warning is made, longest first
+    pub before: Vec<Duration>,
+}
+
+/// How a warning is announced. `{remaining}` is replaced by the time left, like `1 minute`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WarningAction {
+    /// Write `input` to the child's stdin, like `say Server sleeping in {remaining}`
+    Stdin { input: String },
+    /// Run `command`
+    Exec { command: String },
+}
+
+fn schedule<'de, D>(deserializer: D) -> Result<Vec<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut before = Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_duration::parse(s))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(serde::de::Error::custom)?;
+    before.sort_unstable_by(|a, b| b.cmp(a));
+    before.dedup();
+    Ok(before)
+}
+
+/// Writes `d` the way a person would say it, like `5 minutes` or `10 seconds`
+pub fn humanize(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (amount, unit) = match secs {
+        s if s >= 3600 && s % 3600 == 0 => (s / 3600, "hour"),
+        s if s >= 60 && s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    match amount {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
+impl ShutdownWarnings {
+    pub fn validate(&self, idle_timeout: Duration) -> anyhow::Result<()> {
+        if let Some(longest) = self.before.first() {
+            if *longest >= idle_timeout {
+                return Err(anyhow!(
+                    "warning {} before the shutdown doesn't fit in the idle timeout",
+                    humanize(*longest)
+                ));
+            }
+        }
+        if let WarningAction::Exec { command } = &self.action {
+            if shell_words::split(command)?.is_empty() {
+                return Err(anyhow!("exec warning has an empty command"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Announces that the child is stopped in `remaining`
+    pub async fn announce(
+        &self,
+        child: Option<&mut Child>,
+        remaining: Duration,
+    ) -> anyhow::Result<()> {
+        let remaining = humanize(remaining);
+        match &self.action {
+            WarningAction::Stdin { input } => {
+                let child = child.ok_or(anyhow!("there's no child to warn"))?;
+                // a child that doesn't read its stdin can't hold up the service either
+                let line = input.replace("{remaining}", &remaining);
+                tokio::time::timeout(STDIN_TIMEOUT, child.write_line(&line))
+                    .await
+                    .map_err(|_| anyhow!("the child didn't read its stdin"))??;
+            }
+            WarningAction::Exec { command } => {
+                let words = shell_words::split(&command.replace("{remaining}", &remaining))?;
+                // the warning shouldn't hold up the service, so the command runs on its own
+                let mut command = tokio::process::Command::new(&words[0]);
+                command.args(&words[1..]).stdin(Stdio::null());
+                tokio::spawn(async move {
+                    match command.status().await {
+                        Ok(status) if !status.success() => {
+                            warn!("Warning command exited with {}", status)
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Failed to run warning command: {}", e),
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{humanize, ShutdownWarnings, WarningAction};
+
+    #[test]
+    fn humanizes_durations() {
+        assert_eq!(humanize(Duration::from_secs(300)), "5 minutes");
+        assert_eq!(humanize(Duration::from_secs(60)), "1 minute");
+        assert_eq!(humanize(Duration::from_secs(90)), "90 seconds");
+        assert_eq!(humanize(Duration::from_secs(7200)), "2 hours");
+    }
+
+    #[test]
+    fn sorts_schedule() -> anyhow::Result<()> {
+        let warnings: ShutdownWarnings = toml::from_str(
+            r#"
+            type = "stdin"
+            input = "say Server sleeping in {remaining}"
+            before = ["10s", "5m", "1m"]
+            "#,
+        )?;
+
+        assert_eq!(
+            warnings.action,
+            WarningAction::Stdin {
+                input: String::from("say Server sleeping in {remaining}")
+            }
+        );
+        assert_eq!(
+            warnings.before,
+            [
+                Duration::from_secs(300),
+                Duration::from_secs(60),
+                Duration::from_secs(10)
+            ]
+        );
+        assert!(warnings.validate(Duration::from_secs(600)).is_ok());
+        assert!(warnings.validate(Duration::from_secs(300)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::Args;
+
+use crate::ctl::columns;
+use crate::event::{Event, EventKind};
+use crate::top::clock;
+
+#[derive(Clone, Debug, Args)]
+pub struct Simulate {
+    /// Activity to replay: an event log, as written with `--event-log`, or a list of times a
+    /// client was active, one per line as RFC 3339 or Unix seconds
+    activity: PathBuf,
+    #[arg(long)]
+    /// Service of the event log to replay, if it has more than one
+    service: Option<String>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "1m,5m,10m,15m,30m,1h,2h,4h"
+    )]
+    /// Idle timeouts to try, separated by commas
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    idle_timeout: Vec<String>,
+    #[arg(long, value_delimiter = ',', default_value = "10s")]
+    /// Grace periods to try, separated by commas. Stopping the child is assumed to take all of it.
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    grace_period: Vec<String>,
+    #[arg(long)]
+    /// Time the child takes to be ready after it's started
+    ///
+    /// Defaults to the median of the `ready` events in the event log, or 0s. Use `h` for hour, `m`
+    /// for minute, `s` for second or any combination
+    cold_start: Option<String>,
+    #[arg(long)]
+    /// Cold starts a day users put up with, to recommend the idle timeout keeping the child awake
+    /// for the least time without going over it
+    budget: Option<f64>,
+}
+
+/// A time a client was using the service, from its first packet to its last
+type Activity = (DateTime<Utc>, DateTime<Utc>);
+
+/// What the activity and the child's time to be ready were read as
+#[derive(Debug, Default)]
+struct Recording {
+    activity: Vec<Activity>,
+    /// Times the child took to be ready, from the `ready` events
+    ready: Vec<TimeDelta>,
+}
+
+/// Reads `input` as an event log if its first line is a JSON object, or as a list of times
+/// otherwise
+fn read(input: &str, service: Option<&str>) -> anyhow::Result<Recording> {
+    let mut lines = input
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+    let mut recording = Recording::default();
+
+    if !lines.peek().is_some_and(|(_, line)| line.starts_with('{')) {
+        for (number, line) in lines {
+            let time = match line.parse::<f64>() {
+                Ok(seconds) => DateTime::from_timestamp_millis((seconds * 1000.0) as i64),
+                Err(_) => DateTime::parse_from_rfc3339(line).ok().map(|t| t.to_utc()),
+            };
+            let time = time.ok_or(anyhow!("line {} is not a time: {}", number, line))?;
+            recording.activity.push((time, time));
+        }
+        recording.activity.sort();
+        return Ok(recording);
+    }
+
+    let mut events = Vec::new();
+    for (number, line) in lines {
+        let event: Event = serde_json::from_str(line)
+            .map_err(|e| anyhow!("line {} is not an event: {}", number, e))?;
+        events.push(event);
+    }
+    let services: BTreeSet<&str> = events.iter().map(|e| e.service.as_str()).collect();
+    let service = match service {
+        Some(s) if services.contains(s) => s,
+        Some(s) => return Err(anyhow!("there are no events of {}", s)),
+        None if services.len() == 1 => services.first().copied().unwrap_or_default(),
+        None => {
+            let services: Vec<&str> = services.into_iter().collect();
+            return Err(anyhow!(
+                "pick one of the services with --service: {}",
+                services.join(", ")
+            ));
+        }
+    };
+
+    // connections are active for as long as they're open
+    let mut open: HashMap<SocketAddr, DateTime<Utc>> = HashMap::new();
+    for event in events.iter().filter(|e| e.service == service) {
+        match event.kind {
+            EventKind::ConnectionOpened { peer } => {
+                open.insert(peer, event.time);
+            }
+            EventKind::ConnectionClosed { peer, .. } => {
+                let opened = open.remove(&peer).unwrap_or(event.time);
+                recording.activity.push((opened, event.time));
+            }
+            EventKind::Ready { after } => {
+                recording
+                    .ready
+                    .push(TimeDelta::from_std(after).unwrap_or_default());
+            }
+            _ => {}
+        }
+    }
+    recording
+        .activity
+        .extend(open.into_values().map(|t| (t, t)));
+    recording.activity.sort();
+    Ok(recording)
+}
+
+/// How the child would have done with an idle timeout and grace period
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    idle_timeout: TimeDelta,
+    grace_period: TimeDelta,
+    cold_starts: usize,
+    awake: TimeDelta,
+    /// Longest a client waited for the child to be ready
+    worst_wait: TimeDelta,
+}
+
+/// Replays `activity`, sorted by when it started, against a child stopped after `idle_timeout`
+/// without activity, which takes `grace_period` to stop and `cold_start` to be ready again
+fn simulate(
+    activity: &[Activity],
+    idle_timeout: TimeDelta,
+    grace_period: TimeDelta,
+    cold_start: TimeDelta,
+) -> Outcome {
+    let mut outcome = Outcome {
+        idle_timeout,
+        grace_period,
+        cold_starts: 0,
+        awake: TimeDelta::zero(),
+        worst_wait: TimeDelta::zero(),
+    };
+    // when the running child was started, when it was ready, and when it was last active
+    let mut child: Option<(DateTime<Utc>, DateTime<Utc>, DateTime<Utc>)> = None;
+
+    for &(start, end) in activity {
+        let (started, ready, last) = match child {
+            Some((started, ready, last)) if start < last + idle_timeout => {
+                (started, ready, last.max(end))
+            }
+            _ => {
+                // a child that's still stopping has to be gone before the next one starts
+                let stopped = match child {
+                    Some((started, _, last)) => {
+                        let stopped = last + idle_timeout + grace_period;
+                        outcome.awake += stopped - started;
+                        stopped
+                    }
+                    None => start,
+                };
+                let started = start.max(stopped);
+                let ready = started + cold_start;
+                outcome.cold_starts += 1;
+                (started, ready, end.max(ready))
+            }
+        };
+        outcome.worst_wait = outcome.worst_wait.max(ready - start);
+        child = Some((started, ready, last));
+    }
+    if let Some((started, _, last)) = child {
+        outcome.awake += last + idle_timeout + grace_period - started;
+    }
+    outcome
+}
+
+fn parse(duration: &str) -> anyhow::Result<TimeDelta> {
+    Ok(TimeDelta::from_std(parse_duration::parse(duration)?)?)
+}
+
+impl Simulate {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let input = std::fs::read_to_string(&self.activity)
+            .map_err(|e| anyhow!("could not read {}: {}", self.activity.display(), e))?;
+        let mut recording = read(&input, self.service.as_deref())
+            .map_err(|e| anyhow!("could not read {}: {}", self.activity.display(), e))?;
+        let first = match recording.activity.first() {
+            Some(&(first, _)) => first,
+            None => return Err(anyhow!("there's no activity to replay")),
+        };
+        let last = recording
+            .activity
+            .iter()
+            .map(|a| a.1)
+            .max()
+            .unwrap_or(first);
+        let cold_start = match self.cold_start {
+            Some(ref c) => parse(c)?,
+            None => {
+                recording.ready.sort();
+                recording
+                    .ready
+                    .get(recording.ready.len() / 2)
+                    .copied()
+                    .unwrap_or_default()
+            }
+        };
+        // rates are given over at least a day, so a short recording doesn't look busier
+        let days = ((last - first).num_seconds() as f64 / 86400.0).max(1.0);
+
+        let mut outcomes = Vec::new();
+        for idle_timeout in &self.idle_timeout {
+            for grace_period in &self.grace_period {
+                outcomes.push(simulate(
+                    &recording.activity,
+                    parse(idle_timeout)?,
+                    parse(grace_period)?,
+                    cold_start,
+                ));
+            }
+        }
+
+        println!(
+            "Replaying {} times clients were active over {:.1} days, with a cold start of {}",
+            recording.activity.len(),
+            days,
+            clock(cold_start)
+        );
+        let rows: Vec<[String; 7]> = outcomes
+            .iter()
+            .map(|o| {
+                [
+                    clock(o.idle_timeout),
+                    clock(o.grace_period),
+                    o.cold_starts.to_string(),
+                    format!("{:.1}", o.cold_starts as f64 / days),
+                    clock(o.awake),
+                    clock(TimeDelta::seconds(
+                        (o.awake.num_seconds() as f64 / days) as i64,
+                    )),
+                    clock(o.worst_wait),
+                ]
+            })
+            .collect();
+        let header = [
+            "IDLE TIMEOUT",
+            "GRACE",
+            "COLD STARTS",
+            "PER DAY",
+            "AWAKE",
+            "AWAKE A DAY",
+            "WORST WAIT",
+        ];
+        print!("{}", columns(header, &rows));
+
+        let budget = match self.budget {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+        let recommended = outcomes
+            .iter()
+            .filter(|o| o.cold_starts as f64 / days <= budget)
+            .min_by_key(|o| o.awake);
+        match recommended {
+            Some(o) => println!(
+                "Recommended: --idle-timeout {} --grace-period {}, for {:.1} cold starts a day",
+                clock(o.idle_timeout),
+                clock(o.grace_period),
+                o.cold_starts as f64 / days
+            ),
+            None => println!(
+                "None of the idle timeouts keeps under {} cold starts a day, try longer ones",
+                budget
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use super::{read, simulate};
+
+    #[test]
+    fn replays_activity() -> anyhow::Result<()> {
+        let recording = read(
+            "# when the server was used\n\
+             2026-10-12T10:00:00Z\n\
+             2026-10-12T10:20:00Z\n\
+             1791810000\n",
+            None,
+        )?;
+        let times: Vec<DateTime<Utc>> = recording.activity.iter().map(|a| a.0).collect();
+        assert_eq!(
+            times,
+            [
+                "2026-10-12T10:00:00Z".parse::<DateTime<Utc>>()?,
+                "2026-10-12T10:20:00Z".parse()?,
+                "2026-10-12T13:00:00Z".parse()?,
+            ]
+        );
+
+        let minutes = TimeDelta::minutes;
+        // 10:00 wakes it up, it's still up at 10:20, and asleep again by 13:00
+        let outcome = simulate(&recording.activity, minutes(30), minutes(1), minutes(2));
+        assert_eq!(outcome.cold_starts, 2);
+        assert_eq!(outcome.awake, minutes(20 + 31 + 2 + 31));
+        assert_eq!(outcome.worst_wait, minutes(2));
+
+        // the second client shows up while the child is stopping
+        let outcome = simulate(&recording.activity, minutes(15), minutes(5), minutes(2));
+        assert_eq!(outcome.cold_starts, 3);
+        assert_eq!(outcome.worst_wait, minutes(4));
+        Ok(())
+    }
+}
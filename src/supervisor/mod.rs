@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use log::{error, info, warn};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinSet;
+
+use crate::admin;
+use crate::config::{Config, ServiceConfig};
+use crate::dashboard;
+use crate::event::{self, EventBus};
+use crate::ledger;
+use crate::mqtt;
+use crate::service::{Retirement, Service, ServiceControl, ServiceHandle};
+use crate::webhook;
+
+/// Keeps every configured service running and applies configuration reloads to them.
+///
+/// On SIGHUP the config file is read again and diffed against the running services:
+/// - new services are started right away
+/// - removed services stop accepting connections and exit once their child is stopped
+/// - services whose listeners or destination changed keep serving with the old configuration
+///   until their child is stopped, and are then started again with the new one
+/// - any other change (timeouts, command) is applied to the running service
+///
+/// The admin API, the dashboard, the webhooks, the session ledger, the event log and MQTT are only
+/// set up once, and aren't affected by reloads.
+///
+/// On SIGTERM or SIGINT every child is stopped, and the process exits once every service did.
+/// Another signal exits without waiting for them.
+pub struct Supervisor {
+    config_path: Option<PathBuf>,
+    services: HashMap<String, ServiceHandle>,
+    /// Configurations waiting for the running service with the same name to retire
+    pending: HashMap<String, ServiceConfig>,
+    tasks: JoinSet<(String, anyhow::Result<()>)>,
+    /// Published to the admin API whenever a service is started or retired
+    controls: watch::Sender<BTreeMap<String, ServiceControl>>,
+    events: EventBus,
+}
+
+impl Supervisor {
+    pub fn new(config_path: Option<PathBuf>) -> Self {
+        Self {
+            config_path,
+            services: HashMap::new(),
+            pending: HashMap::new(),
+            tasks: JoinSet::new(),
+            controls: watch::Sender::new(BTreeMap::new()),
+            events: EventBus::default(),
+        }
+    }
+
+    fn spawn(&mut self, name: String, config: ServiceConfig) {
+        info!("[{}] Starting service", name);
+        let (service, handle) = Service::new(name.clone(), config, &self.events);
+        let control = handle.control();
+        self.controls.send_modify(|c| {
+            c.insert(name.clone(), control);
+        });
+        self.services.insert(name.clone(), handle);
+        self.tasks.spawn(async move {
+            let result = service.run().await;
+            (name, result)
+        });
+    }
+
+    pub fn apply(&mut self, config: Config) {
+        for (name, handle) in &self.services {
+            if !config.services.contains_key(name) {
+                info!(
+                    "[{}] Service was removed from the config, draining it",
+                    name
+                );
+                self.pending.remove(name);
+                handle.retire(Retirement::Remove);
+            }
+        }
+
+        for (name, new) in config.services {
+            let handle = match self.services.get(&name) {
+                Some(h) => h,
+                None => {
+                    self.spawn(name, new);
+                    continue;
+                }
+            };
+
+            let current = handle.config();
+            if current.requires_restart(&new) || handle.retirement() == Retirement::Remove {
+                info!(
+                    "[{}] Service will be restarted with the new config once its child is stopped",
+                    name
+                );
+                if handle.retirement() != Retirement::Remove {
+                    handle.retire(Retirement::Replace);
+                }
+                self.pending.insert(name, new);
+            } else {
+                if self.pending.remove(&name).is_some() {
+                    info!("[{}] Pending restart was reverted", name);
+                }
+                handle.retire(Retirement::Active);
+                if current != new {
+                    info!("[{}] Applying new config to running service", name);
+                    handle.update(new);
+                }
+            }
+        }
+    }
+
+    fn reload(&mut self) {
+        let path = match self.config_path {
+            Some(ref p) => p,
+            None => {
+                warn!("Got SIGHUP, but there's no config file to reload");
+                return;
+            }
+        };
+
+        info!("Reloading config from {}", path.display());
+        match Config::load(path) {
+            Ok(config) => self.apply(config),
+            Err(e) => error!("Keeping the current config, failed to reload: {}", e),
+        }
+    }
+
+    pub async fn run(mut self, config: Config) -> anyhow::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        admin::serve(&config.admin, self.controls.subscribe(), &self.events).await?;
+        if let Some(ref config) = config.dashboard {
+            dashboard::serve(config, self.controls.subscribe()).await?;
+        }
+        if !config.webhooks.targets.is_empty() {
+            tokio::spawn(webhook::notify(
+                config.webhooks.clone(),
+                self.events.subscribe(),
+            ));
+        }
+        let mut ledger = None;
+        if let Some(ref path) = config.ledger {
+            let (shutdown, stopped) = oneshot::channel();
+            let recorder = tokio::spawn(ledger::record(
+                path.clone(),
+                self.events.subscribe(),
+                stopped,
+            ));
+            ledger = Some((shutdown, recorder));
+        }
+        let mut event_log = None;
+        if let Some(ref path) = config.event_log {
+            let (shutdown, stopped) = oneshot::channel();
+            let logger = tokio::spawn(event::log(path.clone(), self.events.subscribe(), stopped));
+            event_log = Some((shutdown, logger));
+        }
+        if let Some(ref config) = config.mqtt {
+            tokio::spawn(mqtt::run(config.clone(), self.controls.subscribe()));
+        }
+
+        self.apply(config);
+
+        loop {
+            select! {
+                _ = hangup.recv() => {
+                    self.reload();
+                }
+                _ = terminate.recv() => {
+                    info!("Got SIGTERM, stopping every service");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    info!("Got SIGINT, stopping every service");
+                    break;
+                }
+                Some(r) = self.tasks.join_next() => {
+                    let (name, result) = r?;
+                    // A failing service brings the whole process down, just like a single service
+                    // always did
+                    result?;
+
+                    self.services.remove(&name);
+                    self.controls.send_modify(|c| {
+                        c.remove(&name);
+                    });
+                    info!("[{}] Service retired", name);
+                    if let Some(config) = self.pending.remove(&name) {
+                        self.spawn(name, config);
+                    }
+                }
+            }
+        }
+
+        self.pending.clear();
+        for handle in self.services.values() {
+            handle.retire(Retirement::Shutdown);
+        }
+        while !self.tasks.is_empty() {
+            select! {
+                Some(r) = self.tasks.join_next() => {
+                    if let Ok((name, Err(e))) = r {
+                        error!("[{}] Service failed while shutting down: {}", name, e);
+                    }
+                }
+                _ = terminate.recv() => {
+                    warn!("Exiting without waiting for the services to stop");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    warn!("Exiting without waiting for the services to stop");
+                    break;
+                }
+            }
+        }
+        // sessions still going are written as they are
+        if let Some((shutdown, recorder)) = ledger {
+            let _ = shutdown.send(());
+            let _ = recorder.await;
+        }
+        if let Some((shutdown, logger)) = event_log {
+            let _ = shutdown.send(());
+            let _ = logger.await;
+        }
+        info!("Exiting");
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::select;
+use tokio::sync::mpsc;
+
+use crate::admin;
+use crate::ctl::Client;
+use crate::service::ServiceStatus;
+
+/// Throughput samples kept for each service's sparkline
+const SAMPLES: usize = 20;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Clone, Debug, Args)]
+pub struct Top {
+    #[arg(long)]
+    /// Admin socket of the server knocker to watch
+    ///
+    /// Defaults to `$XDG_RUNTIME_DIR/server-knocker.sock`, just like the server's.
+    socket: Option<PathBuf>,
+    #[arg(long, default_value_t = String::from("1s"))]
+    /// Time between refreshes
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    interval: String,
+    #[arg(long, default_value_t = String::from("1h"))]
+    /// How long the `a` key keeps the selected service awake for
+    ///
+    /// Use `h` for hour, `m` for minute, `s` for second or any combination
+    keep_awake: String,
+}
+
+/// Bytes per second proxied by a service, over the last refreshes
+#[derive(Debug, Default)]
+struct Throughput {
+    /// The last total, and when it was sampled
+    last: Option<(u64, Instant)>,
+    rates: VecDeque<u64>,
+}
+
+impl Throughput {
+    /// Records the `total` bytes sampled at `now`. Refreshes don't only happen every interval, key
+    /// presses trigger them too, so the rate is over the time since the last sample.
+    fn record(&mut self, total: u64, now: Instant) {
+        if let Some((last, at)) = self.last {
+            // the counters start over when a service is replaced on reload
+            let bytes = total.saturating_sub(last);
+            let elapsed = now.saturating_duration_since(at);
+            if !elapsed.is_zero() {
+                self.rates
+                    .push_back((bytes as f64 / elapsed.as_secs_f64()) as u64);
+                if self.rates.len() > SAMPLES {
+                    self.rates.pop_front();
+                }
+            }
+        }
+        self.last = Some((total, now));
+    }
+
+    fn sparkline(&self) -> String {
+        let max = self.rates.iter().copied().max().unwrap_or(0);
+        self.rates
+            .iter()
+            .map(|rate| match max {
+                0 => ' ',
+                _ => SPARKS[(*rate * (SPARKS.len() as u64 - 1)).div_ceil(max) as usize],
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct App {
+    statuses: Vec<ServiceStatus>,
+    table: TableState,
+    throughput: HashMap<String, Throughput>,
+    /// Last lines of the selected service's child output
+    logs: Vec<String>,
+    /// Outcome of the last key press, or why the knocker couldn't be reached
+    message: String,
+}
+
+impl App {
+    fn selected(&self) -> Option<&ServiceStatus> {
+        self.statuses.get(self.table.selected()?)
+    }
+
+    fn update(&mut self, statuses: Vec<ServiceStatus>, now: Instant) {
+        for status in &statuses {
+            self.throughput
+                .entry(status.name.clone())
+                .or_default()
+                .record(status.bytes_to_destination + status.bytes_to_client, now);
+        }
+        self.throughput
+            .retain(|name, _| statuses.iter().any(|s| &s.name == name));
+
+        // keep the same service selected when others come and go
+        let selected = self.selected().map(|s| s.name.clone());
+        let index = selected
+            .and_then(|name| statuses.iter().position(|s| s.name == name))
+            .or((!statuses.is_empty()).then_some(0));
+        self.table.select(index);
+        self.statuses = statuses;
+    }
+
+    fn select_next(&mut self) {
+        if !self.statuses.is_empty() {
+            let next = self.table.selected().map_or(0, |i| i + 1);
+            self.table.select(Some(next.min(self.statuses.len() - 1)));
+        }
+    }
+
+    fn select_previous(&mut self) {
+        let previous = self.table.selected().map_or(0, |i| i.saturating_sub(1));
+        self.table.select(Some(previous));
+    }
+
+    fn draw(&mut self, frame: &mut Frame, now: DateTime<Utc>) {
+        let [services, logs, footer] = Layout::vertical([
+            Constraint::Length(self.statuses.len() as u16 + 3),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let rows = self.statuses.iter().map(|s| {
+            let up = s.state.is_up();
+            let throughput = &self.throughput[&s.name];
+            Row::new([
+                s.name.clone(),
+                s.state.as_str().to_owned(),
+                s.started_at
+                    .filter(|_| up)
+                    .map_or(String::from("-"), |t| clock(now - t)),
+                s.idle_deadline
+                    .map_or(String::from("-"), |t| clock(t - now)),
+                (s.connections + s.udp_sessions).to_string(),
+                format!(
+                    "{:<width$} {}",
+                    throughput.sparkline(),
+                    rate(throughput.rates.back().copied().unwrap_or(0)),
+                    width = SAMPLES
+                ),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(10),
+                Constraint::Length(9),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(5),
+                Constraint::Length(SAMPLES as u16 + 11),
+            ],
+        )
+        .header(
+            Row::new([
+                "SERVICE",
+                "STATE",
+                "UPTIME",
+                "IDLE IN",
+                "CONN",
+                "THROUGHPUT",
+            ])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" server-knocker "));
+        frame.render_stateful_widget(table, services, &mut self.table);
+
+        let title = match self.selected() {
+            Some(s) => format!(" {} output ", s.name),
+            None => String::from(" output "),
+        };
+        // only the lines that fit, inside the borders
+        let height = logs.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .logs
+            .iter()
+            .skip(self.logs.len().saturating_sub(height))
+            .map(|l| Line::raw(l.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            logs,
+        );
+
+        let help = "↑↓ select  s start  x stop  a keep awake  r reset idle  q quit";
+        let text = match self.message.as_str() {
+            "" => String::from(help),
+            message => format!("{}  |  {}", help, message),
+        };
+        frame.render_widget(Line::raw(text), footer);
+    }
+}
+
+/// Time left or elapsed, as short as it gets, like `1h05m`
+pub fn clock(delta: TimeDelta) -> String {
+    let seconds = delta.num_seconds().max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m{:02}s", minutes, seconds),
+        _ => format!("{}h{:02}m", hours, minutes),
+    }
+}
+
+/// Bytes per second, like `12.5K/s`
+fn rate(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    for unit in ["B", "K", "M"] {
+        if value < 1024.0 {
+            return format!("{:.1}{}/s", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1}G/s", value)
+}
+
+impl Top {
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let socket = self.socket.clone().unwrap_or_else(admin::default_socket);
+        let client = Client::new(&socket)?;
+        let interval = parse_duration::parse(&self.interval)?;
+        if interval.is_zero() {
+            return Err(anyhow!("the interval between refreshes can't be 0s"));
+        }
+        parse_duration::parse(&self.keep_awake)?;
+
+        // fails before the terminal is taken over if nothing is listening
+        let mut app = App::default();
+        app.update(
+            client.get(Client::url(&["services"])).await?.json().await?,
+            Instant::now(),
+        );
+
+        let mut terminal = ratatui::try_init()?;
+        let result = self.watch(&client, &mut app, &mut terminal, interval).await;
+        ratatui::restore();
+        result
+    }
+
+    async fn watch(
+        &self,
+        client: &Client,
+        app: &mut App,
+        terminal: &mut DefaultTerminal,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        let (sender, mut keys) = mpsc::unbounded_channel();
+        // crossterm only reads the terminal by blocking
+        tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            while !sender.is_closed() {
+                if event::poll(Duration::from_millis(100))? {
+                    if let Event::Key(key) = event::read()? {
+                        if key.kind == KeyEventKind::Press && sender.send(key).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            select! {
+                _ = ticks.tick() => {
+                    self.refresh(client, app).await;
+                }
+                key = keys.recv() => {
+                    let key = match key {
+                        Some(k) => k,
+                        None => return Ok(()),
+                    };
+                    let command = match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            return Ok(())
+                        }
+                        KeyCode::Up | KeyCode::Char('k') => {
+                            app.select_previous();
+                            None
+                        }
+                        KeyCode::Down | KeyCode::Char('j') => {
+                            app.select_next();
+                            None
+                        }
+                        KeyCode::Char('s') => Some(("start", None)),
+                        KeyCode::Char('x') => Some(("stop", None)),
+                        KeyCode::Char('r') => Some(("reset-idle", None)),
+                        KeyCode::Char('a') => Some((
+                            "keep-awake",
+                            Some(serde_json::json!({ "duration": self.keep_awake })),
+                        )),
+                        _ => continue,
+                    };
+
+                    if let (Some((action, body)), Some(service)) = (command, app.selected()) {
+                        let name = service.name.clone();
+                        let url = Client::url(&["services", &name, action]);
+                        app.message = match client.post(url, body).await {
+                            Ok(_) => format!("{}: {}", name, action),
+                            Err(e) => format!("{}: {}", name, e),
+                        };
+                    }
+                    // show the selection and the command's outcome right away
+                    self.refresh(client, app).await;
+                    ticks.reset();
+                }
+            }
+            terminal.draw(|frame| app.draw(frame, Utc::now()))?;
+        }
+    }
+
+    async fn refresh(&self, client: &Client, app: &mut App) {
+        let statuses = match client.get(Client::url(&["services"])).await {
+            Ok(response) => response.json().await,
+            Err(e) => {
+                app.message = e.to_string();
+                return;
+            }
+        };
+        match statuses {
+            Ok(statuses) => app.update(statuses, Instant::now()),
+            Err(e) => app.message = e.to_string(),
+        }
+
+        let name = match app.selected() {
+            Some(s) => s.name.clone(),
+            None => return,
+        };
+        if let Ok(response) = client.get(Client::url(&["services", &name, "logs"])).await {
+            if let Ok(text) = response.text().await {
+                app.logs = text.lines().map(String::from).collect();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use chrono::{TimeDelta, Utc};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use super::{clock, App, Throughput};
+    use crate::service::state::ServiceState;
+    use crate::service::ServiceStatus;
+
+    #[test]
+    fn formats_clock() {
+        assert_eq!(clock(TimeDelta::seconds(42)), "42s");
+        assert_eq!(clock(TimeDelta::seconds(185)), "3m05s");
+        assert_eq!(clock(TimeDelta::seconds(3900)), "1h05m");
+        assert_eq!(clock(TimeDelta::seconds(-5)), "0s");
+    }
+
+    #[test]
+    fn draws_sparkline() {
+        let mut throughput = Throughput::default();
+        let mut now = Instant::now();
+        for total in [0, 0, 100, 150, 350] {
+            throughput.record(total, now);
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(throughput.rates, [0, 100, 50, 200]);
+        assert_eq!(throughput.sparkline(), "▁▅▃█");
+
+        // a refresh right after a key press proxied the same bytes in less time
+        throughput.record(450, now - Duration::from_millis(500));
+        assert_eq!(throughput.rates.back(), Some(&200));
+    }
+
+    #[test]
+    fn draws_services() -> anyhow::Result<()> {
+        let now = Utc::now();
+        let status = ServiceStatus {
+            name: String::from("minecraft"),
+            state: ServiceState::Ready,
+            pid: Some(1234),
+            session_id: Some(1234),
+            started_at: Some(now - TimeDelta::seconds(185)),
+            last_activity: Some(now),
+            idle_deadline: Some(now + TimeDelta::seconds(3900)),
+            keep_awake_until: None,
+            connections: 2,
+            total_connections: 10,
+            udp_sessions: 0,
+            bytes_to_destination: 0,
+            bytes_to_client: 0,
+        };
+        let mut app = App {
+            logs: vec![String::from("Done (3.2s)! For help, type \"help\"")],
+            ..App::default()
+        };
+        app.update(vec![status], Instant::now());
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 12))?;
+        terminal.draw(|frame| app.draw(frame, now))?;
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|c| c.symbol())
+            .collect();
+        for text in [
+            "minecraft",
+            "ready",
+            "3m05s",
+            "1h05m",
+            "minecraft output",
+            "Done (3.2s)!",
+        ] {
+            assert!(screen.contains(text), "{} is not in {}", text, screen);
+        }
+        Ok(())
+    }
+}
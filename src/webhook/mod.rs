@@ -0,0 +1,544 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::duration;
+use crate::event::{Event, EventKind};
+use crate::top::clock;
+
+/// Everything a body template can refer to as `{{name}}`
+const VARIABLES: [&str; 14] = [
+    "service", "event", "time", "message", "client", "reason", "pid", "after", "before", "uptime",
+    "code", "signal", "peer", "bytes",
+];
+
+/// HTTP requests made when something happens to the services, like posting to a chat's incoming
+/// webhook when a server wakes up.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// File every delivery is appended to as a JSON line
+    pub delivery_log: Option<PathBuf>,
+    #[serde(default)]
+    /// Webhooks by name
+    pub targets: BTreeMap<String, Webhook>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Events that trigger the webhook, by their name on the event stream
+    pub events: Vec<String>,
+    #[serde(default)]
+    /// Services whose events trigger the webhook. Every service's do if it's empty.
+    pub services: Vec<String>,
+    /// JSON body, where `{{name}}` is replaced by the event's fields. Defaults to the event itself.
+    pub body: Option<String>,
+    #[serde(default = "default_retries")]
+    /// Attempts made after the first one failed
+    pub retries: u32,
+    #[serde(default = "default_backoff", deserialize_with = "duration")]
+    /// Time before the first retry, doubled for every other one
+    pub backoff: Duration,
+    #[serde(default = "default_timeout", deserialize_with = "duration")]
+    pub timeout: Duration,
+}
+
+fn default_method() -> String {
+    String::from("POST")
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl WebhooksConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, webhook) in &self.targets {
+            webhook
+                .validate()
+                .map_err(|e| anyhow!("webhook {} is invalid: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Webhook {
+    fn validate(&self) -> anyhow::Result<()> {
+        reqwest::Url::parse(&self.url)?;
+        reqwest::Method::from_bytes(self.method.as_bytes())?;
+        for (name, value) in &self.headers {
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())?;
+            reqwest::header::HeaderValue::from_str(value)?;
+        }
+        if self.events.is_empty() {
+            return Err(anyhow!("it has no events"));
+        }
+        for event in &self.events {
+            if !EventKind::NAMES.contains(&event.as_str()) {
+                return Err(anyhow!("there's no event called {}", event));
+            }
+        }
+        if let Some(ref body) = self.body {
+            let variables = VARIABLES.iter().map(|v| (*v, String::from("x"))).collect();
+            serde_json::from_str::<serde_json::Value>(&render(body, &variables)?)
+                .map_err(|e| anyhow!("body is not JSON: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn triggered_by(&self, event: &Event) -> bool {
+        self.events.iter().any(|e| e == event.kind.name())
+            && (self.services.is_empty() || self.services.contains(&event.service))
+    }
+}
+
+/// Replaces every `{{name}}` in `template`, escaping the values to be used inside JSON strings
+fn render(template: &str, variables: &BTreeMap<&str, String>) -> anyhow::Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or(anyhow!("unclosed {{{{ in template"))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = variables
+            .get(name)
+            .ok_or(anyhow!("unknown variable {} in template", name))?;
+        let escaped = serde_json::to_string(value)?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&escaped[1..escaped.len() - 1]);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// When a service started waking up, and for whom
+type Wake = (DateTime<Utc>, Option<SocketAddr>);
+
+/// What a template can say about `event`, and the `wake` of the service it's part of. Fields the
+/// event doesn't have are empty.
+fn variables(event: &Event, wake: Option<Wake>) -> BTreeMap<&'static str, String> {
+    let mut variables: BTreeMap<&str, String> =
+        VARIABLES.iter().map(|v| (*v, String::new())).collect();
+    let service = &event.service;
+    let message = match event.kind {
+        EventKind::ServiceWaking { ref reason, client } => {
+            variables.insert("reason", reason.clone());
+            if let Some(client) = client {
+                variables.insert("client", client.ip().to_string());
+                format!("{} is waking up for {}", service, client.ip())
+            } else {
+                format!("{} is waking up ({})", service, reason)
+            }
+        }
+        EventKind::ChildSpawned { pid } => {
+            variables.insert("pid", pid.to_string());
+            format!("{} started with pid {}", service, pid)
+        }
+        EventKind::Ready { after } => {
+            variables.insert("after", format!("{:.1}", after.as_secs_f64()));
+            format!("{} is ready after {:.1}s", service, after.as_secs_f64())
+        }
+        EventKind::IdleWarning { before } => {
+            variables.insert("before", before.as_secs().to_string());
+            format!("{} stops in {}s for being idle", service, before.as_secs())
+        }
+        EventKind::Stopping { ref reason } => {
+            variables.insert("reason", reason.clone());
+            format!("{} is stopping ({})", service, reason)
+        }
+        EventKind::Exited { code, ref signal } => {
+            let status = match (code, signal) {
+                (Some(code), _) => format!("code {}", code),
+                (None, Some(signal)) => signal.clone(),
+                (None, None) => String::from("an unknown status"),
+            };
+            variables.insert("code", code.map(|c| c.to_string()).unwrap_or_default());
+            variables.insert("signal", signal.clone().unwrap_or_default());
+            format!("{} exited with {}", service, status)
+        }
+        EventKind::ConnectionOpened { peer } => {
+            variables.insert("peer", peer.to_string());
+            format!("{} got a connection from {}", service, peer)
+        }
+        EventKind::ConnectionClosed {
+            peer,
+            bytes_to_destination,
+            bytes_to_client,
+        } => {
+            let bytes = bytes_to_destination + bytes_to_client;
+            variables.insert("peer", peer.to_string());
+            variables.insert("bytes", bytes.to_string());
+            format!(
+                "{} closed a connection from {} ({} bytes)",
+                service, peer, bytes
+            )
+        }
+    };
+    variables.insert("service", service.clone());
+    variables.insert("event", event.kind.name().to_owned());
+    variables.insert("time", event.time.to_rfc3339());
+    variables.insert("message", message);
+    if let Some((woken, client)) = wake {
+        variables.insert("uptime", clock(event.time - woken));
+        if let Some(client) = client {
+            variables.insert("client", client.ip().to_string());
+        }
+    }
+    variables
+}
+
+/// The outcome of a webhook, as written to the delivery log
+#[derive(Debug, Serialize)]
+struct Delivery {
+    time: DateTime<Utc>,
+    webhook: String,
+    service: String,
+    event: &'static str,
+    attempts: u32,
+    /// HTTP status of the last attempt, if there was a response
+    status: Option<u16>,
+    /// Why the last attempt failed, if it did
+    error: Option<String>,
+}
+
+/// Time to wait as told by a `Retry-After` header, either in seconds or until an HTTP date
+fn retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.to_utc() - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Sends `body` to the webhook until it succeeds, it can't, or it ran out of retries. Only
+/// transport errors, server errors and `429 Too Many Requests` are retried.
+async fn deliver(
+    http: reqwest::Client,
+    name: String,
+    webhook: Webhook,
+    event: Event,
+    body: String,
+) -> Delivery {
+    let mut delivery = Delivery {
+        time: Utc::now(),
+        webhook: name,
+        service: event.service,
+        event: event.kind.name(),
+        attempts: 0,
+        status: None,
+        error: None,
+    };
+    let mut backoff = webhook.backoff;
+    loop {
+        delivery.attempts += 1;
+        let mut request = http
+            .request(
+                reqwest::Method::from_bytes(webhook.method.as_bytes()).unwrap_or_default(),
+                &webhook.url,
+            )
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(webhook.timeout)
+            .body(body.clone());
+        for (name, value) in &webhook.headers {
+            request = request.header(name, value);
+        }
+
+        // a request the server refused won't be accepted the next time either, but one it couldn't
+        // handle yet may be, as soon as it says
+        let mut delay = backoff;
+        let retry = match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                delivery.status = Some(status.as_u16());
+                delivery.error =
+                    (!status.is_success()).then(|| format!("responded with {}", status));
+                if let Some(after) = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(retry_after)
+                {
+                    delay = after;
+                }
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                delivery.status = None;
+                delivery.error = Some(e.to_string());
+                true
+            }
+        };
+
+        let error = match delivery.error {
+            None => return delivery,
+            Some(ref e) => e,
+        };
+        if !retry || delivery.attempts > webhook.retries {
+            return delivery;
+        }
+        debug!(
+            "[{}] Webhook {} failed, retrying in {:?}: {}",
+            delivery.service, delivery.webhook, delay, error
+        );
+        tokio::time::sleep(delay).await;
+        backoff *= 2;
+    }
+}
+
+/// Appends every delivery to the log, if there's one
+async fn record(path: Option<PathBuf>, mut deliveries: mpsc::UnboundedReceiver<Delivery>) {
+    while let Some(delivery) = deliveries.recv().await {
+        match delivery.error {
+            None => info!(
+                "[{}] Webhook {} delivered {}",
+                delivery.service, delivery.webhook, delivery.event
+            ),
+            Some(ref e) => warn!(
+                "[{}] Webhook {} failed to deliver {} after {} attempts: {}",
+                delivery.service, delivery.webhook, delivery.event, delivery.attempts, e
+            ),
+        }
+
+        let path = match path {
+            Some(ref p) => p,
+            None => continue,
+        };
+        let line = match serde_json::to_string(&delivery) {
+            Ok(l) => l + "\n",
+            Err(_) => continue,
+        };
+        let written = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(line.as_bytes()).await?;
+            // the write only happens in the background until it's flushed, which could be after
+            // the process exited
+            file.flush().await
+        };
+        if let Err(e) = written.await {
+            error!(
+                "Could not write webhook delivery log {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Calls the webhooks triggered by every event, until there are no more events
+pub async fn notify(config: WebhooksConfig, mut events: broadcast::Receiver<Event>) {
+    let http = reqwest::Client::new();
+    let (deliveries, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(record(config.delivery_log.clone(), receiver));
+    // when each running service started waking up, for the events that come after
+    let mut wakes: HashMap<String, Wake> = HashMap::new();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(e) => e,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhooks missed {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        if let EventKind::ServiceWaking { client, .. } = event.kind {
+            wakes.insert(event.service.clone(), (event.time, client));
+        }
+        let wake = wakes.get(&event.service).copied();
+        if let EventKind::Exited { .. } = event.kind {
+            wakes.remove(&event.service);
+        }
+
+        let variables = variables(&event, wake);
+        for (name, webhook) in &config.targets {
+            if !webhook.triggered_by(&event) {
+                continue;
+            }
+            let body = match webhook.body {
+                Some(ref template) => render(template, &variables),
+                None => serde_json::to_string(&event).map_err(anyhow::Error::from),
+            };
+            let body = match body {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("[{}] Webhook {} has a bad body: {}", event.service, name, e);
+                    continue;
+                }
+            };
+
+            let delivery = deliver(
+                http.clone(),
+                name.clone(),
+                webhook.clone(),
+                event.clone(),
+                body,
+            );
+            let deliveries = deliveries.clone();
+            tokio::spawn(async move {
+                let _ = deliveries.send(delivery.await);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::http::{header, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::{notify, render, retry_after, Webhook, WebhooksConfig};
+    use crate::event::{EventBus, EventKind};
+
+    #[test]
+    fn renders_templates() -> anyhow::Result<()> {
+        let variables = BTreeMap::from([("service", String::from("mine\"craft"))]);
+        assert_eq!(
+            render(r#"{"content": "{{ service }} is up"}"#, &variables)?,
+            r#"{"content": "mine\"craft is up"}"#
+        );
+        assert!(render("{{client}}", &variables).is_err());
+        assert!(render("{{service", &variables).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(retry_after(" 120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn retries_deliveries() -> anyhow::Result<()> {
+        // throttles the first request, fails the second, and keeps the bodies of every request
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(move |body: String| async move {
+                    let mut bodies = received.lock().unwrap();
+                    bodies.push(body);
+                    let status = match bodies.len() {
+                        1 => StatusCode::TOO_MANY_REQUESTS,
+                        2 => StatusCode::INTERNAL_SERVER_ERROR,
+                        _ => StatusCode::NO_CONTENT,
+                    };
+                    (status, [(header::RETRY_AFTER, "0")])
+                }),
+            )
+            .route("/gone", post(|| async { StatusCode::GONE }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let log =
+            std::env::temp_dir().join(format!("knocker-webhooks-{}.jsonl", std::process::id()));
+        let webhook = Webhook {
+            url: format!("http://{}/hook", address),
+            method: String::from("POST"),
+            headers: BTreeMap::new(),
+            events: vec![String::from("ready")],
+            services: vec![],
+            body: Some(String::from(
+                r#"{"content": "{{service}} woken by {{client}} is ready after {{after}}s"}"#,
+            )),
+            retries: 2,
+            // longer than the test waits, so only the server's Retry-After gets it through
+            backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(1),
+        };
+        let gone = Webhook {
+            url: format!("http://{}/gone", address),
+            ..webhook.clone()
+        };
+        let config = WebhooksConfig {
+            delivery_log: Some(log.clone()),
+            targets: BTreeMap::from([
+                (String::from("chat"), webhook),
+                (String::from("gone"), gone),
+            ]),
+        };
+        config.validate()?;
+
+        let bus = EventBus::default();
+        tokio::spawn(notify(config, bus.subscribe()));
+        let events = bus.publisher("minecraft");
+        events.publish(EventKind::ServiceWaking {
+            reason: String::from("wake"),
+            client: Some("192.168.1.20:50000".parse()?),
+        });
+        events.publish(EventKind::Ready {
+            after: Duration::from_secs(41),
+        });
+
+        let mut deliveries = String::new();
+        for _ in 0..100 {
+            deliveries = std::fs::read_to_string(&log).unwrap_or_default();
+            if deliveries.lines().count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&log)?;
+
+        let deliveries: Vec<serde_json::Value> = deliveries
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        let delivery = deliveries.iter().find(|d| d["webhook"] == "chat").unwrap();
+        assert_eq!(delivery["event"], "ready");
+        assert_eq!(delivery["attempts"], 3);
+        assert_eq!(delivery["status"], 204);
+        // a client error isn't retried
+        let delivery = deliveries.iter().find(|d| d["webhook"] == "gone").unwrap();
+        assert_eq!(delivery["attempts"], 1);
+        assert_eq!(delivery["status"], 410);
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(
+            bodies[2],
+            r#"{"content": "minecraft woken by 192.168.1.20 is ready after 41.0s"}"#
+        );
+        Ok(())
+    }
+}